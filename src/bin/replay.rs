@@ -0,0 +1,319 @@
+//! Feeds a recorded chat log through the bot's command handling without
+//! connecting to Twitch, and prints every response the bot would have sent.
+//!
+//! Usage: `replay <log file> [--config <file>] [--data <dir>] [--channel <name>] [--prefix <prefix>] [--permissions <command:role,...>]`
+//!
+//! Each line of the log is either a JSON object
+//! `{"timestamp": "...", "channel": "...", "user": "...", "badges": ["moderator/1"], "text": "..."}`
+//! or plain text `<timestamp> <user> <badges> <text>` where the badges are comma
+//! separated (`-` for none). Empty lines and lines starting with `#` are skipped.
+//! Messages without a channel are sent to the `--channel` one, `replay` by default.
+//!
+//! Queues are stored in a temporary data dir which is removed afterwards. With
+//! `--data` the queues of an existing data dir are copied there first, the
+//! original files are never modified. Queues of single channel data dirs
+//! belong to the `--channel` one. Command aliases and message files are read
+//! from the data dir like the bot does.
+//!
+//! With `--config` the prefixes, permissions, cooldowns, limits and message
+//! files of a bot config are used, `--prefix` and `--permissions` override them.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use twitch_queue_bot::{
+    bot::{
+        store, Aliases, Bot, ChatTransport, Connection, Health, JsonFileStore, Message, Priority,
+        Role, SendResult,
+    },
+    command::{handle_message, Origin, Permissions, Settings},
+    config::{self, Config},
+};
+
+#[derive(Deserialize)]
+struct Entry {
+    #[serde(default)]
+    timestamp: String,
+    #[serde(default)]
+    channel: Option<String>,
+    user: String,
+    #[serde(default)]
+    badges: Vec<String>,
+    text: String,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Result<Self, String> {
+        if line.starts_with('{') {
+            return serde_json::from_str(line).map_err(|e| e.to_string());
+        }
+        let mut fields = line.splitn(4, ' ');
+        let (Some(timestamp), Some(user), Some(badges), Some(text)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err("expected <timestamp> <user> <badges> <text>".into());
+        };
+        let badges = match badges {
+            "-" => Vec::new(),
+            badges => badges.split(',').map(str::to_owned).collect(),
+        };
+        Ok(Self {
+            timestamp: timestamp.to_owned(),
+            channel: None,
+            user: user.to_owned(),
+            badges,
+            text: text.to_owned(),
+        })
+    }
+
+    /// Badges may carry a version like `subscriber/12`, only the name matters
+    fn role(&self) -> Role {
+        Role::from_badges(
+            self.badges
+                .iter()
+                .map(|badge| badge.split('/').next().unwrap_or_default()),
+        )
+    }
+}
+
+/// Transport reading from the log and printing the replies
+struct Replay {
+    entries: VecDeque<Entry>,
+    timestamp: String,
+    health: Health,
+}
+
+#[async_trait]
+impl ChatTransport for Replay {
+    async fn recv_msg(&mut self) -> Option<Message> {
+        let entry = self.entries.pop_front()?;
+        let channel = entry.channel.clone().unwrap_or_default();
+        println!(
+            "[{}] #{channel} <{}> {}",
+            entry.timestamp, entry.user, entry.text
+        );
+        let role = entry.role();
+        self.timestamp = entry.timestamp;
+        Some(Message::UserText {
+            channel,
+            role,
+            user: entry.user.to_lowercase(),
+            text: entry.text,
+            id: None,
+        })
+    }
+
+    async fn send_msg(&self, channel: &str, msg: String, _priority: Priority) -> SendResult {
+        println!("[{}] #{channel} > {msg}", self.timestamp);
+        Ok(())
+    }
+
+    async fn reply_msg(
+        &self,
+        channel: &str,
+        reply_to: &str,
+        msg: String,
+        _priority: Priority,
+    ) -> SendResult {
+        println!(
+            "[{}] #{channel} > (reply to {reply_to}) {msg}",
+            self.timestamp
+        );
+        Ok(())
+    }
+
+    async fn whisper(&self, user: &str, msg: String) -> SendResult {
+        println!("[{}] whisper to {user} > {msg}", self.timestamp);
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SendResult {
+        println!("[{}] Joined #{channel}", self.timestamp);
+        self.health.joined(channel);
+        Ok(())
+    }
+
+    fn part(&mut self, channel: &str) {
+        println!("[{}] Left #{channel}", self.timestamp);
+        self.health.parted(channel);
+    }
+
+    fn health(&self) -> Health {
+        self.health.clone()
+    }
+}
+
+struct Args {
+    log: PathBuf,
+    data: Option<PathBuf>,
+    channel: String,
+    /// The bot config the settings were read from
+    config: Option<Config>,
+    settings: Settings,
+}
+
+fn usage() -> String {
+    "Usage: replay <log file> [--config <file>] [--data <dir>] [--channel <name>] [--prefix <prefix>] [--permissions <command:role,...>]"
+        .into()
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut log = None;
+    let mut data = None;
+    let mut config_path = None;
+    let mut channel = "replay".to_owned();
+    let mut prefix = None;
+    let mut permissions = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().ok_or_else(usage)?),
+            "--data" => data = Some(args.next().ok_or_else(usage)?.into()),
+            "--prefix" => prefix = Some(args.next().ok_or_else(usage)?),
+            "--channel" => channel = args.next().ok_or_else(usage)?.to_lowercase(),
+            "--permissions" => {
+                permissions = Some(Permissions::parse(&args.next().ok_or_else(usage)?)?);
+            }
+            "-h" | "--help" => return Err(usage()),
+            _ if log.is_none() => log = Some(arg.into()),
+            _ => return Err(usage()),
+        }
+    }
+    let config = config_path
+        .map(|path| config::load(["--config".to_owned(), path]))
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    let mut settings = config.as_ref().map(Config::settings).unwrap_or_default();
+    if let Some(prefix) = prefix {
+        settings.prefix = prefix;
+    }
+    if let Some(permissions) = permissions {
+        settings.permissions = permissions;
+    }
+    Ok(Args {
+        log: log.ok_or_else(usage)?,
+        data,
+        channel,
+        config,
+        settings,
+    })
+}
+
+fn read_log(path: &Path) -> Result<VecDeque<Entry>, String> {
+    let log = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    log.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            Entry::parse(line.trim()).map_err(|e| format!("{}:{}: {e}", path.display(), n + 1))
+        })
+        .collect()
+}
+
+/// Copies the queues, journals and channel dirs included, of an existing data dir
+fn copy_queues(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for file in fs::read_dir(from)? {
+        let file = file?;
+        let file_type = file.file_type()?;
+        if file_type.is_dir() {
+            copy_queues(&file.path(), &to.join(file.file_name()))?;
+        } else if file_type.is_file() {
+            fs::copy(file.path(), to.join(file.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+async fn replay(args: Args, dir: &Path) -> Result<(), String> {
+    let mut entries = read_log(&args.log)?;
+    if let Some(data) = &args.data {
+        copy_queues(data, dir).map_err(|e| format!("{}: {e}", data.display()))?;
+        store::adopt_legacy_data(dir, &args.channel, None).map_err(|e| e.to_string())?;
+    }
+    for entry in &mut entries {
+        let channel = entry.channel.get_or_insert_with(|| args.channel.clone());
+        *channel = channel.to_lowercase();
+    }
+    let mut channels: Vec<String> = entries.iter().filter_map(|e| e.channel.clone()).collect();
+    channels.sort();
+    channels.dedup();
+
+    let mut chat = Replay {
+        entries,
+        timestamp: String::new(),
+        health: Health::new(),
+    };
+    chat.health.transition(Connection::Connected);
+    let mut bot = Bot::new(chat);
+    if let Some(config) = &args.config {
+        bot.keep_backups(config.backup_count);
+        bot.set_list_size(config.list_size);
+    }
+    let messages_dir = args
+        .config
+        .as_ref()
+        .map_or(dir, |c| c.messages_dir.as_path());
+    bot.set_template_dir(Some(messages_dir));
+    bot.set_aliases(Aliases::open(dir).map_err(|e| e.to_string())?);
+    for channel in &channels {
+        let store = JsonFileStore::new(dir.join(channel)).map_err(|e| e.to_string())?;
+        bot.add_channel(channel, Box::new(store));
+    }
+    while let Some(Message::UserText {
+        channel,
+        role,
+        user,
+        text,
+        id,
+    }) = bot.recv_msg().await
+    {
+        handle_message(
+            &mut bot,
+            &args.settings,
+            &channel,
+            role,
+            &user,
+            Origin::Chat(id.as_deref()),
+            &text,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        bot.flush_journal();
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .compact()
+        .with_writer(std::io::stderr)
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let dir = std::env::temp_dir().join(format!("queue-replay-{}", std::process::id()));
+    let result = replay(args, &dir).await;
+    let _ = fs::remove_dir_all(&dir);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use super::chat::Role;
+use super::history::{Action, History};
+use super::queue::{PushError, Queue};
+use super::store::{QueueStore, StoreError};
+use super::templates::Templates;
+
+/// How long a chatter's role is trusted after their last message. Badges
+/// aren't sent with whispers, so a mod who lost their role could otherwise
+/// keep using mod commands there for as long as the bot runs.
+const ROLE_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// Formats a duration in its largest whole unit, e.g. `5m`
+pub fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs @ 0..=59 => format!("{secs}s"),
+        secs @ 60..=3599 => format!("{}m", secs / 60),
+        secs @ 3600..=86399 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86400),
+    }
+}
+
+/// Queue state of one chat channel, every command returns the reply to send
+pub struct Channel {
+    pub name: String,
+    pub queue: Option<Queue>,
+    store: Box<dyn QueueStore>,
+    /// Whether the selected queue changed since it was last saved
+    dirty: bool,
+    /// Number of backups kept per queue
+    backup_count: usize,
+    /// Number of people shown by !list
+    list_size: usize,
+    history: History,
+    /// Chatters above [`Role::Everyone`] as of their last message here and
+    /// when it was sent, used for commands that don't come with badges, like whispers
+    roles: HashMap<String, (Role, Instant)>,
+    templates: Templates,
+    /// When commands can be used again, by command and user, or `None`
+    /// for everyone
+    cooldowns: HashMap<(String, Option<String>), Instant>,
+}
+
+impl Channel {
+    pub fn new(
+        name: &str,
+        store: Box<dyn QueueStore>,
+        backup_count: usize,
+        list_size: usize,
+    ) -> Self {
+        debug!("Creating channel {name}");
+        Self {
+            name: name.to_owned(),
+            queue: None,
+            store,
+            dirty: false,
+            backup_count,
+            list_size,
+            history: History::default(),
+            roles: HashMap::new(),
+            templates: Templates::default(),
+            cooldowns: HashMap::new(),
+        }
+    }
+
+    pub fn templates(&self) -> &Templates {
+        &self.templates
+    }
+
+    /// Replaces the messages of this channel
+    pub fn set_templates(&mut self, templates: Templates) {
+        self.templates = templates;
+    }
+
+    /// Remembers the role `user` had in their latest chat message
+    pub fn seen(&mut self, user: &str, role: Role) {
+        let now = Instant::now();
+        self.roles.retain(|_, (_, seen)| now < *seen + ROLE_EXPIRY);
+        if role > Role::Everyone {
+            self.roles.insert(user.to_owned(), (role, now));
+        } else {
+            self.roles.remove(user);
+        }
+    }
+
+    /// The role of `user` as of their latest chat message here,
+    /// [`Role::Everyone`] if that was longer than [`ROLE_EXPIRY`] ago
+    pub fn role(&self, user: &str) -> Role {
+        if user == self.name {
+            return Role::Broadcaster;
+        }
+        match self.roles.get(user) {
+            Some(&(role, seen)) if seen.elapsed() < ROLE_EXPIRY => role,
+            _ => Role::Everyone,
+        }
+    }
+
+    /// Records a use of `command` by `user` unless it is still cooling down,
+    /// `global` after anyone used it or `per_user` after they did.
+    /// Returns whether the command may be used.
+    pub fn try_use(
+        &mut self,
+        command: &str,
+        user: &str,
+        global: Duration,
+        per_user: Duration,
+    ) -> bool {
+        let now = Instant::now();
+        self.cooldowns.retain(|_, ready| *ready > now);
+        let everyone = (command.to_owned(), None);
+        let own = (command.to_owned(), Some(user.to_owned()));
+        if self.cooldowns.contains_key(&everyone) || self.cooldowns.contains_key(&own) {
+            return false;
+        }
+        if !global.is_zero() {
+            self.cooldowns.insert(everyone, now + global);
+        }
+        if !per_user.is_zero() {
+            self.cooldowns.insert(own, now + per_user);
+        }
+        true
+    }
+
+    /// Sets how many backups are kept per queue, 0 disables backups
+    pub fn keep_backups(&mut self, count: usize) {
+        self.backup_count = count;
+    }
+
+    /// Sets how many people !list shows at most
+    pub fn set_list_size(&mut self, size: usize) {
+        self.list_size = size;
+    }
+
+    /// Backs up `queue`, or the selected queue if `None`.
+    /// Called before destructive commands so they can be undone with `!restore`.
+    fn backup(&mut self, queue: Option<&Queue>) {
+        if self.backup_count == 0 {
+            return;
+        }
+        if let Some(queue) = queue.or(self.queue.as_ref()) {
+            if let Err(e) = self.store.backup(queue, self.backup_count) {
+                warn!("Couldn't back up queue {}: {e}", queue.name);
+            }
+        }
+    }
+
+    pub fn autosave(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        debug!("Autosaving queue of channel {}", self.name);
+        if let Err(e) = self.persist() {
+            warn!("Couldn't autosave queue: {e}");
+        }
+    }
+
+    /// Persists the currently selected queue, if any
+    fn persist(&mut self) -> Result<(), StoreError> {
+        match self.queue.as_mut() {
+            None => Ok(()),
+            Some(queue) => {
+                // The snapshot already contains every pending mutation
+                queue.take_journal();
+                self.store.save(queue)?;
+                self.dirty = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Hands the mutations of the selected queue to the store so they
+    /// survive a crash. Should be called after every handled command.
+    pub fn flush_journal(&mut self) {
+        if let Some(queue) = self.queue.as_mut() {
+            // Only mod commands can be undone, and they took their changes already
+            queue.take_changes();
+            let entries = queue.take_journal();
+            if entries.is_empty() {
+                return;
+            }
+            self.dirty = true;
+            if let Err(e) = self.store.append(&queue.name, &entries) {
+                warn!("Couldn't write journal for queue {}: {e}", queue.name);
+            }
+        }
+    }
+
+    /// Records the changes made by the current command so it can be undone
+    fn remember(&mut self, label: String) {
+        if let Some(queue) = self.queue.as_mut() {
+            self.history.push(label, queue.take_changes());
+        }
+    }
+
+    fn set_queue(&mut self, queue: Queue) {
+        self.queue = Some(queue);
+        self.dirty = false;
+        self.history.clear();
+    }
+
+    /// Selects `queue`, saving the previously selected one first
+    fn replace_queue(&mut self, queue: Queue) -> Result<(), StoreError> {
+        let previous = self.persist();
+        self.set_queue(queue);
+        previous
+    }
+
+    pub fn create(&mut self, name: &str, prefix: &str) -> String {
+        if !Queue::is_valid_name(name) {
+            return self.templates.text("queue_name_invalid");
+        }
+        if let Err(e) = self.persist() {
+            warn!("Couldn't save previous queue: {e}");
+        }
+        // Make sure an overwritten queue can be restored
+        let existed = match self.store.load(name) {
+            Ok(Some(existing)) => {
+                self.backup(Some(&existing));
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!("Couldn't load existing queue {name}: {e}");
+                false
+            }
+        };
+        self.set_queue(Queue::new(name));
+        if let Err(e) = self.persist() {
+            warn!("Couldn't save queue {name}: {e}");
+            return self.templates.text("store_error");
+        }
+        let key = if existed && self.backup_count > 0 {
+            "queue_recreated"
+        } else {
+            "queue_created"
+        };
+        self.templates
+            .render(key, &[("queue", &name), ("prefix", &prefix)])
+    }
+
+    pub fn backups(&self) -> String {
+        let Some(queue) = &self.queue else {
+            return self.templates.text("queue_not_loaded");
+        };
+        match self.store.backups(&queue.name) {
+            Err(e) => {
+                warn!("Couldn't list backups of queue {}: {e}", queue.name);
+                self.templates.text("store_error")
+            }
+            Ok(backups) if backups.is_empty() => self
+                .templates
+                .render("no_backups", &[("queue", &queue.name)]),
+            Ok(backups) => {
+                let list = backups
+                    .iter()
+                    .enumerate()
+                    .map(|(i, backup)| {
+                        self.templates.render(
+                            "backup",
+                            &[("index", &(i + 1)), ("age", &format_duration(backup.age()))],
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.templates
+                    .render("backups", &[("queue", &queue.name), ("list", &list)])
+            }
+        }
+    }
+
+    /// Loads the `index`th newest backup of a queue, starting at 1
+    fn load_backup(&self, name: &str, index: usize) -> Result<Option<Queue>, StoreError> {
+        match self.store.backups(name)?.get(index - 1) {
+            None => Ok(None),
+            Some(backup) => self.store.load_backup(name, *backup),
+        }
+    }
+
+    pub fn restore(&mut self, index: usize) -> String {
+        let Some(name) = self.queue.as_ref().map(|queue| queue.name.clone()) else {
+            return self.templates.text("queue_not_loaded");
+        };
+        let restored = match self.load_backup(&name, index) {
+            Ok(Some(restored)) => restored,
+            Ok(None) => {
+                return self
+                    .templates
+                    .render("backup_missing", &[("index", &index), ("queue", &name)])
+            }
+            Err(e) => {
+                warn!("Couldn't load backup {index} of queue {name}: {e}");
+                return self.templates.text("store_error");
+            }
+        };
+
+        // The current state becomes a backup itself, so restoring can be undone
+        self.backup(None);
+        self.set_queue(restored);
+        if let Err(e) = self.persist() {
+            warn!("Couldn't save restored queue {name}: {e}");
+            return self.templates.text("store_error");
+        }
+        let len = self.queue.as_ref().map_or(0, Queue::len);
+        self.templates.render(
+            "restored",
+            &[("queue", &name), ("index", &index), ("count", &len)],
+        )
+    }
+
+    pub fn select(&mut self, name: &str) -> String {
+        if !Queue::is_valid_name(name) {
+            return self.templates.text("queue_name_invalid");
+        }
+        match self.store.load(name) {
+            Ok(Some(queue)) => {
+                if let Err(e) = self.replace_queue(queue) {
+                    warn!("Couldn't save previous queue: {e}");
+                }
+                let name = &self.queue.as_ref().unwrap().name;
+                self.templates.render("queue_selected", &[("queue", name)])
+            }
+            Ok(None) => self.templates.render("queue_missing", &[("queue", &name)]),
+            Err(e) => {
+                warn!("Couldn't load queue {name}: {e}");
+                self.templates.text("store_error")
+            }
+        }
+    }
+
+    pub fn save(&mut self) -> String {
+        match self.persist() {
+            Err(e) => {
+                warn!("Couldn't save queue: {e}");
+                self.templates.text("store_error")
+            }
+            Ok(()) => match &self.queue {
+                None => self.templates.text("queue_not_loaded"),
+                Some(queue) => self
+                    .templates
+                    .render("queue_saved", &[("queue", &queue.name)]),
+            },
+        }
+    }
+
+    pub fn queues(&self) -> String {
+        match self.store.list() {
+            Err(e) => {
+                warn!("Couldn't list queues: {e}");
+                self.templates.text("store_error")
+            }
+            Ok(slugs) if slugs.is_empty() => self.templates.text("no_queues"),
+            Ok(slugs) => self
+                .templates
+                .render("queues", &[("list", &slugs.join(", "))]),
+        }
+    }
+
+    pub fn delete(&mut self, name: &str, prefix: &str) -> String {
+        if !Queue::is_valid_name(name) {
+            return self.templates.text("queue_name_invalid");
+        }
+        if self.queue.as_ref().map(Queue::slug) == Some(Queue::slugify(name)) {
+            return self.templates.text("selected_not_deleted");
+        }
+        // Make sure a deleted queue can be restored, like every other destructive command
+        let queue = match self.store.load(name) {
+            Ok(Some(queue)) => queue,
+            Ok(None) => return self.templates.render("queue_missing", &[("queue", &name)]),
+            Err(e) => {
+                warn!("Couldn't load queue {name}: {e}");
+                return self.templates.text("store_error");
+            }
+        };
+        if self.backup_count > 0 {
+            if let Err(e) = self.store.backup(&queue, self.backup_count) {
+                warn!("Couldn't back up queue {name}, not deleting it: {e}");
+                return self.templates.text("store_error");
+            }
+        }
+        match self.store.delete(name) {
+            Err(e) => {
+                warn!("Couldn't delete queue {name}: {e}");
+                self.templates.text("store_error")
+            }
+            Ok(false) => self.templates.render("queue_missing", &[("queue", &name)]),
+            Ok(true) => {
+                let key = if self.backup_count > 0 {
+                    "queue_deleted_backup"
+                } else {
+                    "queue_deleted"
+                };
+                self.templates
+                    .render(key, &[("queue", &name), ("prefix", &prefix)])
+            }
+        }
+    }
+
+    pub fn open(&mut self) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match queue.open() {
+                Err(()) => self.templates.text("queue_open_error"),
+                Ok(()) => self.templates.text("queue_open"),
+            },
+        }
+    }
+
+    pub fn close(&mut self) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match queue.close() {
+                Err(()) => self.templates.text("queue_close_error"),
+                Ok(()) => self.templates.text("queue_close"),
+            },
+        }
+    }
+
+    pub fn clear(&mut self) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(_) => {
+                self.backup(None);
+                self.queue.as_mut().unwrap().clear();
+                self.remember("clear".into());
+                self.templates.text("queue_clear")
+            }
+        }
+    }
+
+    pub fn join(&mut self, user: &str, nickname: Option<&str>) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => {
+                if let Some(nickname) = nickname {
+                    queue.set_name(user, nickname);
+                }
+                if queue.is_open {
+                    match queue.push(user) {
+                        Err(PushError::Played) => {
+                            self.templates.render("already_played", &[("user", &user)])
+                        }
+                        Err(PushError::Present(idx)) => self.templates.render(
+                            "already_queued",
+                            &[
+                                ("user", &user),
+                                ("position", &(idx + 1)),
+                                ("queue", &queue.name),
+                            ],
+                        ),
+                        Ok(idx) => self.templates.render(
+                            "joined",
+                            &[
+                                ("user", &user),
+                                ("position", &(idx + 1)),
+                                ("queue", &queue.name),
+                            ],
+                        ),
+                    }
+                } else {
+                    self.templates.text("queue_closed")
+                }
+            }
+        }
+    }
+
+    pub fn leave(&mut self, user: &str) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match queue.remove(user) {
+                Ok(()) => self.templates.render("left", &[("user", &user)]),
+                Err(()) => self.templates.render("not_queued", &[("user", &user)]),
+            },
+        }
+    }
+
+    pub fn name(&mut self, user: &str, name: Option<&str>) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match name {
+                Some(name) => {
+                    queue.set_name(user, name);
+                    self.templates
+                        .render("name_changed", &[("user", &user), ("name", &name)])
+                }
+                None => match queue.player_name(user) {
+                    None => self.templates.render("name_not_set", &[("user", &user)]),
+                    Some(name) => self
+                        .templates
+                        .render("name", &[("user", &user), ("name", &name)]),
+                },
+            },
+        }
+    }
+
+    pub fn reset(&mut self) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(_) => {
+                self.backup(None);
+                self.queue.as_mut().unwrap().reset();
+                self.remember("reset".into());
+                self.templates.text("player_history_reset")
+            }
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match queue.shift() {
+                None => self.templates.text("queue_empty"),
+                Some(user) => {
+                    let next_msg = match queue.player_name(&user) {
+                        None => self.templates.render("next", &[("user", &user)]),
+                        Some(name) => self
+                            .templates
+                            .render("next_named", &[("user", &user), ("name", &name)]),
+                    };
+                    let after = match queue.first() {
+                        None => self.templates.text("next_last"),
+                        Some(next) => self.templates.render("next_after", &[("user", next)]),
+                    };
+                    let msg = format!("{next_msg} {after}");
+                    self.remember(format!("next (@{user})"));
+                    msg
+                }
+            },
+        }
+    }
+
+    pub fn undo(&mut self, prefix: &str) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match self.history.pop_undo() {
+                None => self.templates.text("nothing_to_undo"),
+                Some(action) => {
+                    for change in action.changes.iter().rev() {
+                        if let Some(inverse) = &change.inverse {
+                            queue.apply(inverse);
+                        }
+                    }
+                    queue.take_changes();
+                    let label = format!("{prefix}{}", action.label);
+                    let msg = self.templates.render("undid", &[("action", &label)]);
+                    self.history.push_redo(action);
+                    msg
+                }
+            },
+        }
+    }
+
+    pub fn redo(&mut self, prefix: &str) -> String {
+        match self.queue.as_mut() {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match self.history.pop_redo() {
+                None => self.templates.text("nothing_to_redo"),
+                Some(action) => {
+                    for change in &action.changes {
+                        queue.apply(&change.op);
+                    }
+                    let label = format!("{prefix}{}", action.label);
+                    let msg = self.templates.render("redid", &[("action", &label)]);
+                    // The queue may have changed since, so the inverses are recorded anew
+                    self.history.push_undo(Action {
+                        label: action.label,
+                        changes: queue.take_changes(),
+                    });
+                    msg
+                }
+            },
+        }
+    }
+
+    pub fn position(&self, user: &str) -> String {
+        match &self.queue {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => match queue.find(user) {
+                Some(idx) => self.templates.render(
+                    "position",
+                    &[
+                        ("user", &user),
+                        ("position", &(idx + 1)),
+                        ("queue", &queue.name),
+                    ],
+                ),
+                None => self
+                    .templates
+                    .render("position_not_queued", &[("user", &user)]),
+            },
+        }
+    }
+
+    pub fn length(&self) -> String {
+        match &self.queue {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => self.templates.render("length", &[("count", &queue.len())]),
+        }
+    }
+
+    /// Lists the first people in queue, or page `page` of the whole queue
+    /// with `list_size` people per page
+    pub fn list(&self, page: Option<usize>) -> String {
+        fn format_list<T: AsRef<str> + std::fmt::Display>(l: &[T], first: usize) -> String {
+            l.iter()
+                .enumerate()
+                .map(|(i, s)| format!("[{}. {}]", first + i + 1, s))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+
+        match &self.queue {
+            None => self.templates.text("queue_not_loaded"),
+            Some(queue) => {
+                let max = self.list_size;
+                let l = queue.list();
+                debug!("Full list: {l:?}");
+                let pages = (l.len() + max - 1) / max;
+                match (l.len(), page) {
+                    (0, _) => self.templates.text("queue_empty"),
+                    (n, None) if n <= max => self
+                        .templates
+                        .render("list", &[("list", &format_list(l, 0))]),
+                    (n, None) => self.templates.render(
+                        "list_first",
+                        &[
+                            ("count", &max),
+                            ("total", &n),
+                            ("list", &format_list(&l[..max], 0)),
+                        ],
+                    ),
+                    (_, Some(page)) if page > pages => match pages {
+                        1 => self.templates.text("only_page"),
+                        pages => self.templates.render("only_pages", &[("pages", &pages)]),
+                    },
+                    (n, Some(page)) => {
+                        let first = (page - 1) * max;
+                        let last = (first + max).min(n);
+                        self.templates.render(
+                            "list_page",
+                            &[
+                                ("page", &page),
+                                ("pages", &pages),
+                                ("total", &n),
+                                ("list", &format_list(&l[first..last], first)),
+                            ],
+                        )
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if let Err(e) = self.persist() {
+            warn!(
+                "Couldn't save queue of channel {} on shutdown: {e}",
+                self.name
+            );
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::{sleep, timeout},
+};
+use tracing::{debug, info, warn};
+use twitch_irc::{message::ServerMessage, ClientConfig, Error, SecureWSTransport, TwitchIRCClient};
+
+use super::health::{Connection, Health};
+use super::helix::Helix;
+use super::login::{Credentials, Login};
+use super::outbox::{Outbox, Pending, Priority, WhisperLimit};
+
+#[cfg(test)]
+mod tests;
+
+type Transport = SecureWSTransport;
+type IRCError = Error<Transport, Credentials>;
+type IRCClient = TwitchIRCClient<Transport, Credentials>;
+
+#[derive(Debug)]
+pub enum Message {
+    UserText {
+        channel: String,
+        role: Role,
+        user: String,
+        text: String,
+        /// Id of the chat message, replies can be threaded under it
+        id: Option<String>,
+    },
+    /// A private message to the bot
+    Whisper { user: String, text: String },
+    /// The connection is back after it was lost
+    Reconnected,
+}
+
+/// Authority of a chatter in the channel, from least to most privileged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl Role {
+    /// Returns the highest role granted by any of the badges
+    pub fn from_badges<'a>(badges: impl IntoIterator<Item = &'a str>) -> Self {
+        badges
+            .into_iter()
+            .map(|badge| match badge {
+                "broadcaster" => Self::Broadcaster,
+                "moderator" => Self::Moderator,
+                "vip" => Self::Vip,
+                "subscriber" | "founder" => Self::Subscriber,
+                _ => Self::Everyone,
+            })
+            .max()
+            .unwrap_or(Self::Everyone)
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "everyone" => Ok(Self::Everyone),
+            "subscriber" | "sub" => Ok(Self::Subscriber),
+            "vip" => Ok(Self::Vip),
+            "moderator" | "mod" => Ok(Self::Moderator),
+            "broadcaster" => Ok(Self::Broadcaster),
+            other => Err(format!("Unknown role {other}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let name = match self {
+            Self::Everyone => "everyone",
+            Self::Subscriber => "subscriber",
+            Self::Vip => "vip",
+            Self::Moderator => "moderator",
+            Self::Broadcaster => "broadcaster",
+        };
+        write!(fmt, "{name}")
+    }
+}
+
+pub type Reader = UnboundedReceiver<ServerMessage>;
+
+#[derive(Debug)]
+pub enum SendError {
+    ClientError(IRCError),
+    ClientClosed,
+    InvalidChannel(twitch_irc::validate::Error),
+    /// The whisper API rejected the message
+    Whisper(String),
+}
+
+pub type SendResult = Result<(), SendError>;
+
+#[derive(Clone)]
+pub struct Config {
+    pub login: Login,
+    pub bot_username: String,
+    pub channels: Vec<String>,
+    /// Verified bots may send far more messages
+    pub verified: bool,
+}
+
+impl From<IRCError> for SendError {
+    fn from(error: IRCError) -> Self {
+        Self::ClientError(error)
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::ClientClosed => write!(fmt, "Client closed"),
+            Self::ClientError(e) => e.fmt(fmt),
+            Self::InvalidChannel(e) => e.fmt(fmt),
+            Self::Whisper(e) => write!(fmt, "Couldn't whisper: {e}"),
+        }
+    }
+}
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Twitch rejects longer chat messages
+pub const MAX_MESSAGE_LEN: usize = 500;
+
+/// Splits `msg` into parts that fit into a chat message, breaking between
+/// list entries if possible, otherwise between words
+pub fn split_message(msg: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = msg.trim();
+    while rest.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let head = &rest[..end];
+        let (part, next) = if let Some(i) = head.rfind(", ").filter(|&i| i > 0) {
+            (&rest[..i], &rest[i + 2..])
+        } else if let Some(i) = head.rfind(' ').filter(|&i| i > 0) {
+            (&rest[..i], &rest[i + 1..])
+        } else {
+            (head, &rest[end..])
+        };
+        parts.push(part.to_owned());
+        rest = next.trim_start();
+    }
+    parts.push(rest.to_owned());
+    parts
+}
+
+/// A chat connection the bot reads commands from and sends its replies to
+#[async_trait]
+pub trait ChatTransport: Send {
+    /// Waits for the next message, `None` means the chat has been closed
+    async fn recv_msg(&mut self) -> Option<Message>;
+    async fn send_msg(&self, channel: &str, msg: String, priority: Priority) -> SendResult;
+    /// Sends `msg` as a reply threaded under the message with id `reply_to`
+    async fn reply_msg(
+        &self,
+        channel: &str,
+        reply_to: &str,
+        msg: String,
+        priority: Priority,
+    ) -> SendResult;
+    /// Sends `msg` privately to `user`, who whispered to the bot before
+    async fn whisper(&self, user: &str, msg: String) -> SendResult;
+    /// Starts receiving messages from `channel`
+    fn join(&mut self, channel: &str) -> SendResult;
+    /// Stops receiving messages from `channel`
+    fn part(&mut self, channel: &str);
+    fn health(&self) -> Health;
+}
+
+impl Config {
+    pub fn new<'a>(
+        login: Login,
+        bot_username: &str,
+        channels: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        Self {
+            login,
+            bot_username: bot_username.to_lowercase(),
+            channels: channels
+                .into_iter()
+                .map(|channel| channel.trim().to_lowercase())
+                .collect(),
+            verified: false,
+        }
+    }
+}
+
+/// Handed to the task sending queued messages
+enum Outgoing {
+    Message(Pending),
+    Moderator { channel: String, moderator: bool },
+}
+
+/// Sends the messages of `outbox` as fast as the rate limits allow,
+/// until the client is closed
+async fn send_queued(client: IRCClient, mut outbox: Outbox, mut rx: UnboundedReceiver<Outgoing>) {
+    loop {
+        while let Some(pending) = outbox.pop(Instant::now()) {
+            let Pending {
+                channel,
+                text,
+                reply_to,
+                ..
+            } = pending;
+            info!(channel, reply_to, "Sending: {text}");
+            let sent = match reply_to {
+                None => client.say(channel, text).await,
+                Some(id) => client.say_in_reply_to(&(channel, id), text).await,
+            };
+            if let Err(e) = sent {
+                warn!("Couldn't send message: {e}");
+            }
+        }
+        let wait = outbox.next_send(Instant::now());
+        if wait.is_some() {
+            debug!(?wait, pending = outbox.len(), "Rate limited");
+        }
+        tokio::select! {
+            outgoing = rx.recv() => match outgoing {
+                None => break,
+                Some(Outgoing::Message(message)) => outbox.push(message),
+                Some(Outgoing::Moderator { channel, moderator }) => {
+                    outbox.set_moderator(&channel, moderator);
+                }
+            },
+            () = sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+        }
+    }
+    if !outbox.is_empty() {
+        warn!("Chat closed, {} messages weren't sent", outbox.len());
+    }
+}
+
+/// A whisper waiting to be sent through Helix
+struct Whisper {
+    user_id: String,
+    text: String,
+}
+
+/// Sends whispers one after another as fast as their rate limits allow,
+/// until the client is closed
+async fn send_whispers(helix: Helix, mut rx: UnboundedReceiver<Whisper>) {
+    let mut limit = WhisperLimit::new();
+    while let Some(Whisper { user_id, text }) = rx.recv().await {
+        let wait = limit.wait(Instant::now());
+        if !wait.is_zero() {
+            debug!(?wait, "Whispers rate limited");
+            sleep(wait).await;
+        }
+        limit.take(Instant::now());
+        if let Err(e) = helix.whisper(&user_id, &text).await {
+            warn!("Couldn't whisper: {e}");
+        }
+    }
+}
+
+pub struct Client {
+    reader: Reader,
+    client: Option<IRCClient>,
+    outbox: Option<UnboundedSender<Outgoing>>,
+    whispers: Option<UnboundedSender<Whisper>>,
+    /// User ids of the users who whispered to the bot, by login
+    whisperers: HashMap<String, String>,
+    bot_username: String,
+    health: Health,
+    pub closed: Arc<AtomicBool>,
+}
+
+impl Client {
+    pub fn new(config: Config) -> Self {
+        info!("Creating twitch chat client");
+        let closed = Arc::new(AtomicBool::new(false));
+        let creds = Credentials::new(&config.bot_username, config.login);
+        let helix = Helix::new(creds.clone());
+        let irc_config = ClientConfig::new_simple(creds);
+        let (reader, client) = TwitchIRCClient::<Transport, _>::new(irc_config);
+        let (outbox, rx) = mpsc::unbounded_channel();
+        let queue = Outbox::new(config.verified);
+        tokio::spawn(send_queued(client.clone(), queue, rx));
+        let (whispers, rx) = mpsc::unbounded_channel();
+        tokio::spawn(send_whispers(helix, rx));
+
+        let mut health = Health::new();
+        for channel in &config.channels {
+            health.joining(channel);
+            client.join(channel.clone()).expect("Couldn't join channel");
+        }
+
+        debug!("Creating chat client");
+        Self {
+            reader,
+            client: Some(client),
+            outbox: Some(outbox),
+            whispers: Some(whispers),
+            whisperers: HashMap::new(),
+            bot_username: config.bot_username,
+            health,
+            closed,
+        }
+    }
+
+    /// Hands a message to the task sending them within the rate limits
+    fn queue(&self, message: Pending) -> SendResult {
+        match &self.outbox {
+            Some(outbox) if outbox.send(Outgoing::Message(message)).is_ok() => Ok(()),
+            _ => Err(SendError::ClientClosed),
+        }
+    }
+
+    /// Updates the connection health, returning the messages the bot handles
+    fn handle(&mut self, line: ServerMessage) -> Option<Message> {
+        self.health.received();
+        match line {
+            ServerMessage::Privmsg(msg) => {
+                let user = msg.sender.login;
+                let channel = msg.channel_login;
+                let mod_tag = msg.source.tags.0.get("mod");
+                debug!(?mod_tag, badges = ?msg.badges);
+                let text = msg.message_text;
+                let mut role =
+                    Role::from_badges(msg.badges.iter().map(|badge| badge.name.as_str()));
+                if user == channel {
+                    role = Role::Broadcaster;
+                } else if mod_tag == Some(&Some(String::from("1"))) {
+                    role = role.max(Role::Moderator);
+                }
+                return Some(Message::UserText {
+                    channel,
+                    role,
+                    user,
+                    text,
+                    id: Some(msg.message_id),
+                });
+            }
+            ServerMessage::Whisper(msg) => {
+                info!("> Whisper ({}): {}", msg.sender.login, msg.message_text);
+                let user = msg.sender.login;
+                self.whisperers.insert(user.clone(), msg.sender.id);
+                return Some(Message::Whisper {
+                    user,
+                    text: msg.message_text,
+                });
+            }
+            // Sent after every successful login, including those of the extra connections
+            // the client opens when busy. Only a login after the connection was lost,
+            // i.e. after a RECONNECT or a stale connection, is a reconnect.
+            ServerMessage::GlobalUserState(_) => {
+                let previous = self.health.transition(Connection::Connected);
+                return (previous == Connection::Reconnecting).then_some(Message::Reconnected);
+            }
+            ServerMessage::Join(msg) if msg.user_login == self.bot_username => {
+                self.health.joined(&msg.channel_login);
+            }
+            ServerMessage::RoomState(msg) => self.health.joined(&msg.channel_login),
+            ServerMessage::Part(msg) if msg.user_login == self.bot_username => {
+                self.health.parted(&msg.channel_login);
+            }
+            // Sent after joining and after every message the bot sends
+            ServerMessage::UserState(msg) => {
+                let role = Role::from_badges(msg.badges.iter().map(|badge| badge.name.as_str()));
+                if let Some(outbox) = &self.outbox {
+                    let _ = outbox.send(Outgoing::Moderator {
+                        channel: msg.channel_login,
+                        moderator: role >= Role::Moderator,
+                    });
+                }
+            }
+            ServerMessage::Reconnect(_) => {
+                warn!("Server asked to reconnect");
+                self.health.transition(Connection::Reconnecting);
+            }
+            ServerMessage::Notice(msg) => {
+                let id = msg.message_id.as_deref().unwrap_or_default();
+                match (msg.channel_login, id) {
+                    (
+                        Some(channel),
+                        "msg_banned" | "msg_channel_suspended" | "tos_ban" | "msg_room_not_found",
+                    ) => self.health.failed(&channel, id),
+                    (channel, "msg_ratelimit" | "msg_duplicate" | "msg_slowmode") => {
+                        warn!(?channel, "Message dropped by chat: {}", msg.message_text);
+                    }
+                    (channel, _) => warn!(?channel, id, "Notice: {}", msg.message_text),
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl ChatTransport for Client {
+    async fn recv_msg(&mut self) -> Option<Message> {
+        loop {
+            if self.closed.load(Ordering::Relaxed) && self.client.is_some() {
+                debug!("Chat closed, dropping client");
+                self.client = None;
+                self.outbox = None;
+                self.whispers = None;
+                self.health.transition(Connection::Closed);
+            }
+            self.health.check();
+            if let Ok(msg) = timeout(TIMEOUT, self.reader.recv()).await {
+                match msg {
+                    None => return None,
+                    Some(line) => {
+                        debug!("> {line:?}");
+                        if let Some(msg) = self.handle(line) {
+                            return Some(msg);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn send_msg(&self, channel: &str, msg: String, priority: Priority) -> SendResult {
+        debug!("Queueing");
+        self.queue(Pending::new(channel, msg, priority))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn reply_msg(
+        &self,
+        channel: &str,
+        reply_to: &str,
+        msg: String,
+        priority: Priority,
+    ) -> SendResult {
+        debug!("Queueing");
+        self.queue(Pending::new(channel, msg, priority).in_reply_to(reply_to))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn whisper(&self, user: &str, msg: String) -> SendResult {
+        debug!("Queueing");
+        let Some(user_id) = self.whisperers.get(user) else {
+            return Err(SendError::Whisper(format!(
+                "{user} hasn't whispered to the bot"
+            )));
+        };
+        let whisper = Whisper {
+            user_id: user_id.clone(),
+            text: msg,
+        };
+        match &self.whispers {
+            Some(whispers) if whispers.send(whisper).is_ok() => Ok(()),
+            _ => Err(SendError::ClientClosed),
+        }
+    }
+
+    fn join(&mut self, channel: &str) -> SendResult {
+        let Some(client) = &self.client else {
+            return Err(SendError::ClientClosed);
+        };
+        client
+            .join(channel.to_owned())
+            .map_err(SendError::InvalidChannel)?;
+        self.health.joining(channel);
+        Ok(())
+    }
+
+    fn part(&mut self, channel: &str) {
+        info!("Leaving channel {channel}");
+        if let Some(client) = &self.client {
+            client.part(channel.to_owned());
+        }
+        self.health.parted(channel);
+    }
+
+    fn health(&self) -> Health {
+        self.health.clone()
+    }
+}
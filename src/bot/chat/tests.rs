@@ -0,0 +1,62 @@
+use twitch_irc::message::{IRCMessage, ServerMessage};
+
+use super::{Client, Config, Connection, Message, Role};
+use crate::bot::Login;
+
+/// A client that never connects, for feeding it server messages
+fn client() -> Client {
+    Client::new(Config {
+        login: Login::Static {
+            oauth_token: "token".into(),
+        },
+        bot_username: "queuebot".into(),
+        channels: Vec::new(),
+        verified: false,
+    })
+}
+
+fn server_message(line: &str) -> ServerMessage {
+    ServerMessage::try_from(IRCMessage::parse(line).unwrap()).unwrap()
+}
+
+fn global_user_state() -> ServerMessage {
+    server_message("@badge-info=;badges=;color=;display-name=QueueBot;emote-sets=0;user-id=1;user-type= :tmi.twitch.tv GLOBALUSERSTATE")
+}
+
+#[tokio::test]
+async fn only_logins_after_a_lost_connection_are_reconnects() {
+    let mut client = client();
+    assert!(client.handle(global_user_state()).is_none());
+    assert_eq!(client.health.connection(), Connection::Connected);
+
+    // The client opens more connections when busy, each one logs in
+    for _ in 0..3 {
+        assert!(client.handle(global_user_state()).is_none());
+    }
+    assert_eq!(client.health.reconnects(), 0);
+
+    client.handle(server_message(":tmi.twitch.tv RECONNECT"));
+    assert_eq!(client.health.connection(), Connection::Reconnecting);
+    assert!(matches!(
+        client.handle(global_user_state()),
+        Some(Message::Reconnected)
+    ));
+    assert_eq!(client.health.connection(), Connection::Connected);
+    assert_eq!(client.health.reconnects(), 1);
+    assert!(client.handle(global_user_state()).is_none());
+}
+
+#[test]
+fn roles_from_badges() {
+    assert_eq!(Role::from_badges([]), Role::Everyone);
+    assert_eq!(
+        Role::from_badges(["subscriber", "premium"]),
+        Role::Subscriber
+    );
+    assert_eq!(Role::from_badges(["vip", "founder"]), Role::Vip);
+    assert_eq!(
+        Role::from_badges(["subscriber", "moderator"]),
+        Role::Moderator
+    );
+    assert_eq!(Role::from_badges(["broadcaster"]), Role::Broadcaster);
+}
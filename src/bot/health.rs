@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+/// The chat server sends a PONG every 30 seconds, a silent connection is stuck
+const STALE_AFTER: Duration = Duration::from_secs(90);
+/// How long a JOIN may take before the channel is considered failed
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connection {
+    /// Waiting for the first login to succeed
+    Connecting,
+    Connected,
+    /// The connection was lost or the server asked to reconnect
+    Reconnecting,
+    Closed,
+}
+
+impl std::fmt::Display for Connection {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let name = match self {
+            Self::Connecting => "connecting",
+            Self::Connected => "connected",
+            Self::Reconnecting => "reconnecting",
+            Self::Closed => "closed",
+        };
+        write!(fmt, "{name}")
+    }
+}
+
+/// Whether the bot is in a channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Membership {
+    Joining(Instant),
+    Joined,
+    /// Holding the reason, e.g. the id of a NOTICE like `msg_banned`
+    Failed(String),
+}
+
+/// Connection state machine of a chat transport, every transition is logged
+#[derive(Clone, Debug)]
+pub struct Health {
+    connection: Connection,
+    /// When the connection entered its current state
+    since: Instant,
+    last_message: Instant,
+    reconnects: usize,
+    channels: BTreeMap<String, Membership>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            connection: Connection::Connecting,
+            since: now,
+            last_message: now,
+            reconnects: 0,
+            channels: BTreeMap::new(),
+        }
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection(&self) -> Connection {
+        self.connection
+    }
+
+    /// Time spent in the current connection state
+    pub fn uptime(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    pub fn channel(&self, channel: &str) -> Option<&Membership> {
+        self.channels.get(channel)
+    }
+
+    /// Moves to `connection`, returning the previous state
+    pub fn transition(&mut self, connection: Connection) -> Connection {
+        let previous = self.connection;
+        if previous == connection || previous == Connection::Closed {
+            return previous;
+        }
+        match connection {
+            Connection::Reconnecting | Connection::Closed => {
+                warn!("Connection {previous} -> {connection}")
+            }
+            _ => info!("Connection {previous} -> {connection}"),
+        }
+        if previous == Connection::Reconnecting && connection == Connection::Connected {
+            self.reconnects += 1;
+        }
+        self.connection = connection;
+        self.since = Instant::now();
+        previous
+    }
+
+    /// Records that the server is still talking to us
+    pub fn received(&mut self) {
+        self.last_message = Instant::now();
+    }
+
+    pub fn joining(&mut self, channel: &str) {
+        info!("Joining channel {channel}");
+        self.channels
+            .insert(channel.to_owned(), Membership::Joining(Instant::now()));
+    }
+
+    pub fn joined(&mut self, channel: &str) {
+        if self.channels.get(channel) != Some(&Membership::Joined) {
+            info!("Joined channel {channel}");
+            self.channels.insert(channel.to_owned(), Membership::Joined);
+        }
+    }
+
+    pub fn failed(&mut self, channel: &str, reason: &str) {
+        warn!("Couldn't join channel {channel}: {reason}");
+        self.channels
+            .insert(channel.to_owned(), Membership::Failed(reason.to_owned()));
+    }
+
+    pub fn parted(&mut self, channel: &str) {
+        if self.channels.remove(channel).is_some() {
+            info!("Left channel {channel}");
+        }
+    }
+
+    /// Detects stuck connections and joins that were never confirmed
+    pub fn check(&mut self) {
+        if self.connection == Connection::Connected && self.last_message.elapsed() > STALE_AFTER {
+            warn!(
+                "No messages from chat for {}s",
+                self.last_message.elapsed().as_secs()
+            );
+            self.transition(Connection::Reconnecting);
+        }
+        let timed_out: Vec<_> = self
+            .channels
+            .iter()
+            .filter(|(_, membership)| {
+                matches!(membership, Membership::Joining(since) if since.elapsed() > JOIN_TIMEOUT)
+            })
+            .map(|(channel, _)| channel.clone())
+            .collect();
+        for channel in timed_out {
+            self.failed(&channel, "no confirmation from the server");
+        }
+    }
+}
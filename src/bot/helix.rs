@@ -0,0 +1,92 @@
+//! The parts of the Helix API chat can't do, which is sending whispers
+
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use tracing::debug;
+use twitch_irc::login::LoginCredentials;
+
+use super::login::Credentials;
+
+const VALIDATE_URL: &str = "https://id.twitch.tv/oauth2/validate";
+const WHISPERS_URL: &str = "https://api.twitch.tv/helix/whispers";
+
+/// What the token belongs to, both are needed for every request
+#[derive(Debug, Deserialize)]
+struct TokenInfo {
+    client_id: String,
+    user_id: String,
+}
+
+/// Helix client using the chat credentials of the bot. Sending whispers
+/// requires the `user:manage:whispers` scope on the token.
+pub struct Helix {
+    http: reqwest::Client,
+    credentials: Credentials,
+    token_info: OnceCell<TokenInfo>,
+}
+
+impl Helix {
+    pub fn new(credentials: Credentials) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            credentials,
+            token_info: OnceCell::new(),
+        }
+    }
+
+    async fn token(&self) -> Result<String, String> {
+        let pair = self
+            .credentials
+            .get_credentials()
+            .await
+            .map_err(|e| e.to_string())?;
+        let token = pair.token.ok_or("No token to call the API with")?;
+        Ok(token.trim_start_matches("oauth:").to_owned())
+    }
+
+    /// Looks up who the token belongs to, once
+    async fn token_info(&self, token: &str) -> Result<&TokenInfo, String> {
+        self.token_info
+            .get_or_try_init(|| async {
+                debug!("Validating token");
+                let response = self
+                    .http
+                    .get(VALIDATE_URL)
+                    .header("Authorization", format!("OAuth {token}"))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("Couldn't validate token: {e}"))?;
+                response
+                    .json::<TokenInfo>()
+                    .await
+                    .map_err(|e| format!("Couldn't validate token: {e}"))
+            })
+            .await
+    }
+
+    /// Whispers `msg` to the user with the id `to_user_id`
+    pub async fn whisper(&self, to_user_id: &str, msg: &str) -> Result<(), String> {
+        let token = self.token().await?;
+        let info = self.token_info(&token).await?;
+        let response = self
+            .http
+            .post(WHISPERS_URL)
+            .query(&[
+                ("from_user_id", &info.user_id),
+                ("to_user_id", &to_user_id.to_owned()),
+            ])
+            .bearer_auth(&token)
+            .header("Client-Id", &info.client_id)
+            .json(&serde_json::json!({ "message": msg }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(format!("Whisper rejected with {status}: {body}"))
+    }
+}
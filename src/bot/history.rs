@@ -0,0 +1,56 @@
+use super::queue::Change;
+
+/// Number of actions that can be undone
+const MAX_UNDO: usize = 20;
+
+/// A mod command and the queue mutations it caused
+pub struct Action {
+    /// The command as shown in replies, without the prefix
+    pub label: String,
+    pub changes: Vec<Change>,
+}
+
+/// Undo and redo stacks for the selected queue
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Action>,
+    redo: Vec<Action>,
+}
+
+impl History {
+    /// Records a new action, which makes the redo stack obsolete
+    pub fn push(&mut self, label: String, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.push_undo(Action { label, changes });
+    }
+
+    pub fn push_undo(&mut self, action: Action) {
+        if action.changes.is_empty() {
+            return;
+        }
+        if self.undo.len() == MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.undo.push(action);
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Action> {
+        self.undo.pop()
+    }
+
+    pub fn push_redo(&mut self, action: Action) {
+        self.redo.push(action);
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Action> {
+        self.redo.pop()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use tracing::{debug, info};
+use twitch_irc::login::{
+    CredentialsPair, LoginCredentials, RefreshingLoginCredentials, RefreshingLoginError,
+    StaticLoginCredentials, TokenStorage, UserAccessToken,
+};
+
+/// How the bot logs into chat
+#[derive(Clone, Debug)]
+pub enum Login {
+    /// A token that is used until it expires
+    Static { oauth_token: String },
+    /// A token kept in `storage` that is refreshed before it expires
+    Refreshing {
+        client_id: String,
+        client_secret: String,
+        storage: FileTokenStorage,
+    },
+}
+
+/// Credentials handed to the chat client, built from a [`Login`]
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    Static(StaticLoginCredentials),
+    Refreshing(RefreshingLoginCredentials<FileTokenStorage>),
+}
+
+impl Credentials {
+    pub fn new(bot_username: &str, login: Login) -> Self {
+        match login {
+            Login::Static { oauth_token } => Self::Static(StaticLoginCredentials::new(
+                bot_username.to_owned(),
+                Some(oauth_token),
+            )),
+            Login::Refreshing {
+                client_id,
+                client_secret,
+                storage,
+            } => Self::Refreshing(RefreshingLoginCredentials::init_with_username(
+                Some(bot_username.to_owned()),
+                client_id,
+                client_secret,
+                storage,
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl LoginCredentials for Credentials {
+    type Error = RefreshingLoginError<FileTokenStorage>;
+
+    async fn get_credentials(&self) -> Result<CredentialsPair, Self::Error> {
+        match self {
+            Self::Static(credentials) => Ok(credentials.credentials.clone()),
+            Self::Refreshing(credentials) => credentials.get_credentials().await,
+        }
+    }
+}
+
+/// Keeps the user access token in a JSON file, which is replaced atomically
+/// whenever the token is refreshed
+#[derive(Clone, Debug)]
+pub struct FileTokenStorage {
+    path: PathBuf,
+}
+
+impl FileTokenStorage {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Stores a refresh token obtained elsewhere, the access token is
+    /// fetched with it when the bot connects
+    pub fn seed(&self, refresh_token: &str) -> io::Result<()> {
+        info!("Storing refresh token in {}", self.path.display());
+        let now = Utc::now();
+        self.write(&UserAccessToken {
+            access_token: String::new(),
+            refresh_token: refresh_token.to_owned(),
+            created_at: now,
+            // Already expired, so it is refreshed right away
+            expires_at: Some(now),
+        })
+    }
+
+    fn write(&self, token: &UserAccessToken) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::DirBuilder::new().recursive(true).create(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // The file holds secrets, only the bot's user may read it
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        let data = serde_json::to_vec_pretty(token).map_err(io::Error::from)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[async_trait]
+impl TokenStorage for FileTokenStorage {
+    type LoadError = io::Error;
+    type UpdateError = io::Error;
+
+    async fn load_token(&mut self) -> Result<UserAccessToken, io::Error> {
+        let data = fs::read_to_string(&self.path)?;
+        serde_json::from_str(&data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    async fn update_token(&mut self, token: &UserAccessToken) -> Result<(), io::Error> {
+        debug!("Saving refreshed token to {}", self.path.display());
+        self.write(token)
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::chat::{ChatTransport, Message, Role, SendResult};
+use super::health::{Connection, Health};
+use super::outbox::Priority;
+
+/// Offline transport replaying scripted messages and recording the replies
+pub struct MockTransport {
+    incoming: VecDeque<Message>,
+    sent: Mutex<Vec<(String, String)>>,
+    /// Ids of the messages replied to, in the order of the replies
+    replied_to: Mutex<Vec<String>>,
+    /// Whispers sent, with the users they were sent to
+    whispered: Mutex<Vec<(String, String)>>,
+    /// Channels currently joined
+    joined: Vec<String>,
+    /// Wait for messages forever instead of ending the chat once none are left
+    pub idle: bool,
+    pub health: Health,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        let mut health = Health::new();
+        health.transition(Connection::Connected);
+        Self {
+            incoming: VecDeque::new(),
+            sent: Mutex::default(),
+            replied_to: Mutex::default(),
+            whispered: Mutex::default(),
+            joined: Vec::new(),
+            idle: false,
+            health,
+        }
+    }
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a chat message to be received by the bot
+    pub fn inject(&mut self, channel: &str, role: Role, user: &str, text: &str) {
+        self.incoming.push_back(Message::UserText {
+            channel: channel.to_owned(),
+            role,
+            user: user.to_owned(),
+            text: text.to_owned(),
+            id: None,
+        });
+    }
+
+    /// Queues a chat message with an id, so replies can be threaded under it
+    pub fn inject_with_id(&mut self, channel: &str, role: Role, user: &str, text: &str, id: &str) {
+        self.incoming.push_back(Message::UserText {
+            channel: channel.to_owned(),
+            role,
+            user: user.to_owned(),
+            text: text.to_owned(),
+            id: Some(id.to_owned()),
+        });
+    }
+
+    /// Returns the channels and messages sent since the last call
+    pub fn take_sent(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.sent.lock().unwrap())
+    }
+
+    /// Queues a whisper to be received by the bot
+    pub fn inject_whisper(&mut self, user: &str, text: &str) {
+        self.incoming.push_back(Message::Whisper {
+            user: user.to_owned(),
+            text: text.to_owned(),
+        });
+    }
+
+    /// Returns the users and whispers sent since the last call
+    pub fn take_whispered(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.whispered.lock().unwrap())
+    }
+
+    /// Returns the ids of the messages replied to since the last call
+    pub fn take_replied_to(&self) -> Vec<String> {
+        std::mem::take(&mut self.replied_to.lock().unwrap())
+    }
+
+    pub fn joined(&self) -> &[String] {
+        &self.joined
+    }
+}
+
+#[async_trait]
+impl ChatTransport for MockTransport {
+    async fn recv_msg(&mut self) -> Option<Message> {
+        match self.incoming.pop_front() {
+            None if self.idle => std::future::pending().await,
+            msg => msg,
+        }
+    }
+
+    async fn send_msg(&self, channel: &str, msg: String, _priority: Priority) -> SendResult {
+        self.sent.lock().unwrap().push((channel.to_owned(), msg));
+        Ok(())
+    }
+
+    async fn reply_msg(
+        &self,
+        channel: &str,
+        reply_to: &str,
+        msg: String,
+        _priority: Priority,
+    ) -> SendResult {
+        self.replied_to.lock().unwrap().push(reply_to.to_owned());
+        self.sent.lock().unwrap().push((channel.to_owned(), msg));
+        Ok(())
+    }
+
+    async fn whisper(&self, user: &str, msg: String) -> SendResult {
+        self.whispered.lock().unwrap().push((user.to_owned(), msg));
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SendResult {
+        self.joined.push(channel.to_owned());
+        self.health.joined(channel);
+        Ok(())
+    }
+
+    fn part(&mut self, channel: &str) {
+        self.joined.retain(|c| c != channel);
+        self.health.parted(channel);
+    }
+
+    fn health(&self) -> Health {
+        self.health.clone()
+    }
+}
@@ -1,280 +1,446 @@
+mod channel;
 pub mod chat;
+mod health;
+mod helix;
+mod history;
+mod login;
+#[cfg(test)]
+pub mod mock;
+mod outbox;
 mod queue;
+pub mod store;
+mod templates;
 
-pub use chat::{Client as ChatClient, Config, Message, SendError, SendResult};
+pub use channel::{format_duration, Channel};
+pub use chat::{
+    split_message, ChatTransport, Client as ChatClient, Config, Message, Role, SendError,
+    SendResult, MAX_MESSAGE_LEN,
+};
+pub use health::{Connection, Health, Membership};
+pub use login::{FileTokenStorage, Login};
+pub use outbox::{Priority, RateLimit};
 pub use queue::{PushError, Queue};
-use tracing::{debug, warn};
-
-mod messages {
-    pub const QUEUE_NOT_LOADED: &str = "No Queue selected";
-    pub const QUEUE_CLOSED: &str = "Queue is currently closed";
-    pub const QUEUE_CLOSE: &str = "Queue has been closed";
-    pub const QUEUE_CLOSE_ERROR: &str = "Queue is already closed";
-    pub const QUEUE_OPEN: &str = "Queue is now open";
-    pub const QUEUE_OPEN_ERROR: &str = "Queue is already open";
-    pub const QUEUE_CLEAR: &str = "Queue has been cleared";
-    pub const QUEUE_EMPTY: &str = "The queue is currently empty";
-    pub const PLAYER_HISTORY_RESET: &str = "Player history has been reset!";
-}
+pub use store::{
+    Aliases, ChannelList, JsonFileStore, MemoryStore, QueueStore, StoreError, StoreResult,
+};
+pub use templates::{TemplateError, Templates};
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+pub const DEFAULT_BACKUP_COUNT: usize = 5;
+pub const DEFAULT_LIST_SIZE: usize = 5;
+
+/// Creates the queue store of a newly joined channel
+pub type StoreFactory = Box<dyn Fn(&str) -> StoreResult<Box<dyn QueueStore>> + Send>;
 
-pub struct Bot {
-    pub chat: ChatClient,
-    pub queue: Option<Queue>,
+/// Lets streamers add the bot to their own channel from its home channel
+struct ChannelAdmin {
+    home: String,
+    /// Channels joined with !addme
+    added: ChannelList,
+    open_store: StoreFactory,
 }
 
-impl Bot {
-    pub fn new(config: Config) -> Self {
-        debug!("Creating data dir {}", queue::DATA_DIR);
-        std::fs::DirBuilder::new()
-            .recursive(true)
-            .create(queue::DATA_DIR)
-            .unwrap();
+/// Connection to chat, routing commands to the state of the channel they came from
+pub struct Bot<T: ChatTransport = ChatClient> {
+    pub chat: T,
+    channels: HashMap<String, Channel>,
+    autosave: Option<mpsc::Receiver<()>>,
+    /// Number of backups kept per queue
+    backup_count: usize,
+    /// Number of people shown by !list
+    list_size: usize,
+    admin: Option<ChannelAdmin>,
+    /// Channel whispers are about unless they name one
+    whisper_channel: Option<String>,
+    /// Directory holding the per channel message files
+    template_dir: Option<PathBuf>,
+    /// Messages used outside of any known channel
+    templates: Templates,
+    aliases: Aliases,
+}
 
+impl<T: ChatTransport> Bot<T> {
+    pub fn new(chat: T) -> Self {
         debug!("Creating bot");
         Self {
-            chat: ChatClient::new(config),
-            queue: None,
+            chat,
+            channels: HashMap::new(),
+            autosave: None,
+            backup_count: DEFAULT_BACKUP_COUNT,
+            list_size: DEFAULT_LIST_SIZE,
+            admin: None,
+            whisper_channel: None,
+            template_dir: None,
+            templates: Templates::default(),
+            aliases: Aliases::in_memory(),
         }
     }
 
-    pub async fn recv_msg(&mut self) -> Option<Message> {
-        self.chat.recv_msg().await
-    }
-
-    pub async fn send_msg(&self, msg: String) -> SendResult {
-        match self.chat.send_msg(msg).await {
-            Err(SendError::ClientClosed) => {
-                warn!("Client has been closed, will not be sent");
-                Ok(())
+    /// Allows `!addme` and `!removeme` in the `home` channel.
+    /// Joins the channels in `added` right away, later ones are recorded there.
+    pub fn enable_channel_admin(
+        &mut self,
+        home: &str,
+        added: ChannelList,
+        open_store: StoreFactory,
+    ) -> SendResult {
+        let home = home.to_lowercase();
+        for channel in std::iter::once(&home).chain(added.channels()) {
+            if self.channels.contains_key(channel) {
+                continue;
+            }
+            match open_store(channel) {
+                Ok(store) => {
+                    self.chat.join(channel)?;
+                    self.add_channel(channel, store);
+                }
+                Err(e) => {
+                    warn!("Couldn't open the queues of channel {channel}, not joining it: {e}")
+                }
             }
-            other => other,
         }
+        self.admin = Some(ChannelAdmin {
+            home,
+            added,
+            open_store,
+        });
+        Ok(())
     }
 
-    pub async fn create(&mut self, name: &str) -> SendResult {
-        self.queue = Some(Queue::new(name));
-        self.send_msg(format!("Queue \"{name}\" has been created and selected"))
-            .await
+    /// Starts handling commands from `channel`, with its queues kept in `store`
+    pub fn add_channel(&mut self, channel: &str, store: Box<dyn QueueStore>) {
+        let channel = channel.to_lowercase();
+        let mut state = Channel::new(&channel, store, self.backup_count, self.list_size);
+        state.set_templates(self.load_templates(&channel));
+        self.channels.insert(channel, state);
     }
 
-    pub async fn select(&mut self, name: &str) -> SendResult {
-        match Queue::load(name) {
-            Some(queue) => {
-                self.queue = Some(queue);
-                let name = &self.queue.as_ref().unwrap().name;
-                Ok(self
-                    .send_msg(format!("Queue \"{name}\" is now selected"))
-                    .await?)
-            }
-            None => Ok(self
-                .send_msg(format!("A queue named {name} doesn't exist"))
-                .await?),
-        }
+    /// Reads the messages of each channel from `<dir>/<channel>/messages.toml`,
+    /// now and for channels added later. Without a directory all use English.
+    pub fn set_template_dir(&mut self, dir: Option<&Path>) {
+        self.template_dir = dir.map(Path::to_owned);
+        self.reload_templates();
     }
 
-    pub async fn save(&mut self) -> SendResult {
-        match &self.queue {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => {
-                queue.save();
-                self.send_msg(format!("Queue {} saved", queue.name)).await?;
-                Ok(())
+    /// Reads the message files of all channels again
+    pub fn reload_templates(&mut self) {
+        let names: Vec<String> = self.channels.keys().cloned().collect();
+        for name in names {
+            let templates = self.load_templates(&name);
+            if let Some(channel) = self.channels.get_mut(&name) {
+                channel.set_templates(templates);
             }
         }
     }
 
-    pub async fn open(&mut self) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => match queue.open() {
-                Err(()) => Ok(self.send_msg(messages::QUEUE_OPEN_ERROR.into()).await?),
-                Ok(()) => Ok(self.send_msg(messages::QUEUE_OPEN.into()).await?),
-            },
+    fn load_templates(&self, channel: &str) -> Templates {
+        let Some(dir) = &self.template_dir else {
+            return Templates::default();
+        };
+        let path = store::channel_dir(dir, channel).join(templates::FILE);
+        Templates::load(&path).unwrap_or_else(|e| {
+            warn!(
+                "Couldn't read {}, using the default messages: {e}",
+                path.display()
+            );
+            Templates::default()
+        })
+    }
+
+    /// The messages of `channel`, English if it isn't known
+    pub fn templates(&self, channel: &str) -> &Templates {
+        self.channels
+            .get(channel)
+            .map_or(&self.templates, Channel::templates)
+    }
+
+    pub fn channel(&self, channel: &str) -> Option<&Channel> {
+        self.channels.get(channel)
+    }
+
+    pub fn channel_mut(&mut self, channel: &str) -> Option<&mut Channel> {
+        self.channels.get_mut(channel)
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &str> {
+        self.channels.keys().map(String::as_str)
+    }
+
+    /// Replaces the command aliases of all channels
+    pub fn set_aliases(&mut self, aliases: Aliases) {
+        self.aliases = aliases;
+    }
+
+    /// Reads the command aliases again, keeping the current ones on error
+    pub fn reload_aliases(&mut self) {
+        if let Err(e) = self.aliases.reload() {
+            warn!("Couldn't read command aliases, keeping the current ones: {e}");
         }
     }
 
-    pub async fn close(&mut self) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => match queue.close() {
-                Err(()) => Ok(self.send_msg(messages::QUEUE_CLOSE_ERROR.into()).await?),
-                Ok(()) => Ok(self.send_msg(messages::QUEUE_CLOSE.into()).await?),
-            },
+    pub fn aliases(&self) -> &Aliases {
+        &self.aliases
+    }
+
+    pub fn aliases_mut(&mut self) -> &mut Aliases {
+        &mut self.aliases
+    }
+
+    /// Sets the channel whispers are about if they don't start with `#channel`.
+    /// Without one, whispers must name the channel unless the bot is in only one.
+    pub fn set_whisper_channel(&mut self, channel: Option<&str>) {
+        self.whisper_channel = channel.map(str::to_lowercase);
+    }
+
+    /// Splits a whisper into the channel it is about and the rest of it
+    pub fn whisper_channel<'a>(&self, text: &'a str) -> Option<(String, &'a str)> {
+        if let Some(rest) = text.trim_start().strip_prefix('#') {
+            let (channel, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            let channel = channel.to_lowercase();
+            return self
+                .channels
+                .contains_key(&channel)
+                .then(|| (channel, rest.trim_start()));
         }
+        let channel = match &self.whisper_channel {
+            Some(channel) => channel.clone(),
+            None if self.channels.len() == 1 => self.channels.keys().next()?.clone(),
+            None => return None,
+        };
+        Some((channel, text))
     }
 
-    pub async fn clear(&mut self) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => {
-                queue.clear();
-                self.send_msg(messages::QUEUE_CLEAR.into()).await
+    /// Joins the channel of `user`, who sent `!addme` in `channel`
+    pub fn add_me(&mut self, channel: &str, user: &str, prefix: &str) -> String {
+        let templates = self.templates(channel).clone();
+        let Some(admin) = self.admin.as_mut() else {
+            return templates.text("add_disabled");
+        };
+        if channel != admin.home {
+            return templates.render(
+                "add_elsewhere",
+                &[("user", &user), ("home", &admin.home), ("prefix", &prefix)],
+            );
+        }
+        if self.channels.contains_key(user) {
+            return templates.render("add_present", &[("user", &user)]);
+        }
+        let store = match (admin.open_store)(user) {
+            Ok(store) => store,
+            Err(e) => {
+                warn!("Couldn't open the queues of channel {user}: {e}");
+                return templates.render("add_failed", &[("user", &user)]);
             }
+        };
+        if let Err(e) = self.chat.join(user) {
+            warn!("Couldn't join channel {user}: {e}");
+            return templates.render("add_failed", &[("user", &user)]);
+        }
+        if let Err(e) = admin.added.add(user) {
+            warn!("Couldn't save channel list: {e}");
         }
+        info!("Added channel {user}");
+        self.add_channel(user, store);
+        templates.render("added", &[("user", &user), ("prefix", &prefix)])
     }
 
-    pub async fn join(&mut self, user: &str, nickname: Option<&str>) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => {
-                if let Some(nickname) = nickname {
-                    queue.names.insert(user.to_owned(), nickname.to_owned());
+    /// Leaves the channel of `user`, who sent `!removeme` in `channel`
+    pub fn remove_me(&mut self, channel: &str, user: &str, prefix: &str) -> String {
+        let templates = self.templates(channel).clone();
+        let Some(admin) = self.admin.as_mut() else {
+            return templates.text("remove_disabled");
+        };
+        if channel != admin.home {
+            return templates.render(
+                "remove_elsewhere",
+                &[("user", &user), ("home", &admin.home), ("prefix", &prefix)],
+            );
+        }
+        if !admin.added.contains(user) {
+            let key = if self.channels.contains_key(user) {
+                "remove_configured"
+            } else {
+                "remove_absent"
+            };
+            return templates.render(key, &[("user", &user)]);
+        }
+        if let Err(e) = admin.added.remove(user) {
+            warn!("Couldn't save channel list: {e}");
+        }
+        info!("Removed channel {user}");
+        self.chat.part(user);
+        // Dropping the channel saves its selected queue
+        self.channels.remove(user);
+        templates.render("removed", &[("user", &user)])
+    }
+
+    /// Describes the connection and the queue of `channel`
+    pub fn status(&self, channel: &str) -> String {
+        let templates = self.templates(channel);
+        let health = self.chat.health();
+        let connection = templates.get(match health.connection() {
+            Connection::Connecting => "connection_connecting",
+            Connection::Connected => "connection_connected",
+            Connection::Reconnecting => "connection_reconnecting",
+            Connection::Closed => "connection_closed",
+        });
+        let mut status = templates.render(
+            "status",
+            &[
+                ("connection", &connection),
+                ("uptime", &format_duration(health.uptime())),
+            ],
+        );
+        match health.reconnects() {
+            0 => {}
+            1 => status.push_str(templates.get("status_reconnect")),
+            n => status.push_str(&templates.render("status_reconnects", &[("count", &n)])),
+        }
+        if let Some(membership) = health.channel(channel) {
+            let membership = match membership {
+                Membership::Joining(_) => templates.text("membership_joining"),
+                Membership::Joined => templates.text("membership_joined"),
+                Membership::Failed(reason) => {
+                    templates.render("membership_failed", &[("reason", reason)])
                 }
-                if queue.is_open {
-                    match queue.push(user) {
-                        Err(PushError::Played) => {
-                            self.send_msg(format!(
-                            "@{user}: You've already played. Wait until queue reset to join again.",
-                        ))
-                            .await
-                        }
-                        Err(PushError::Present(idx)) => {
-                            self.send_msg(format!(
-                                "@{user}: You're already in queue at position {}",
-                                idx + 1
-                            ))
-                            .await
-                        }
-                        Ok(idx) => {
-                            self.send_msg(format!(
-                                "@{user}: You've been added to the queue at position {}",
-                                idx + 1
-                            ))
-                            .await
-                        }
-                    }
+            };
+            status.push_str(&templates.render("status_channel", &[("membership", &membership)]));
+        }
+        match self.channel(channel).and_then(|state| state.queue.as_ref()) {
+            None => status.push_str(templates.get("status_no_queue")),
+            Some(queue) => {
+                let key = if queue.is_open {
+                    "status_open"
                 } else {
-                    Ok(self.send_msg(messages::QUEUE_CLOSED.into()).await?)
-                }
+                    "status_closed"
+                };
+                status.push_str(
+                    &templates.render(key, &[("queue", &queue.name), ("count", &queue.len())]),
+                );
             }
         }
+        status
     }
 
-    pub async fn leave(&mut self, user: &str) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => match queue.remove(user) {
-                Ok(()) => {
-                    self.send_msg(format!("@{user}: You've been removed from the queue"))
-                        .await
-                }
-                Err(()) => self.send_msg(format!("@{user}: You were not queued")).await,
-            },
+    /// Applies `Channel::keep_backups` to every channel, including later ones
+    pub fn keep_backups(&mut self, count: usize) {
+        self.backup_count = count;
+        for channel in self.channels.values_mut() {
+            channel.keep_backups(count);
         }
     }
 
-    pub async fn name(&mut self, user: &str, name: Option<&str>) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => match name {
-                Some(name) => {
-                    queue.names.insert(user.to_owned(), name.to_owned());
-                    self.send_msg(format!("@{user}: Player name changed to '{name}'"))
-                        .await
-                }
-                None => match queue.names.get(user) {
-                    None => self.send_msg(format!("@{user}: Player name not set")).await,
-                    Some(name) => {
-                        let msg = format!("@{user}: Current player name is '{name}'");
-                        self.send_msg(msg).await
-                    }
-                },
-            },
+    /// Applies `Channel::set_list_size` to every channel, including later ones
+    pub fn set_list_size(&mut self, size: usize) {
+        self.list_size = size;
+        for channel in self.channels.values_mut() {
+            channel.set_list_size(size);
         }
     }
 
-    pub async fn reset(&mut self) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => {
-                queue.reset();
-                self.send_msg(messages::PLAYER_HISTORY_RESET.into()).await
+    /// Saves the selected queues every `period` if they have been changed
+    pub fn enable_autosave(&mut self, period: Duration) {
+        debug!("Saving queues every {period:?}");
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if tx.send(()).await.is_err() {
+                    break;
+                }
             }
-        }
+        });
+        self.autosave = Some(rx);
     }
 
-    pub async fn next(&mut self) -> SendResult {
-        match self.queue.as_mut() {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => match queue.shift() {
-                None => self.send_msg(messages::QUEUE_EMPTY.into()).await,
-                Some(user) => {
-                    let next_msg = match queue.names.get(&user) {
-                        None => format!("@{user} is next!"),
-                        Some(name) => format!("@{user} ({name}) is next!"),
-                    };
-                    match queue.first() {
-                        None => {
-                            self.send_msg(format!("{next_msg} That's the last one."))
-                                .await
-                        }
-                        Some(user) => {
-                            let user = user.to_owned();
-                            self.send_msg(format!("{next_msg} @{user} is up after that."))
-                                .await
-                        }
-                    }
-                }
-            },
+    pub fn disable_autosave(&mut self) {
+        debug!("Disabling autosave");
+        self.autosave = None;
+    }
+
+    fn autosave(&mut self) {
+        for channel in self.channels.values_mut() {
+            channel.autosave();
         }
     }
 
-    pub async fn position(&self, user: &str) -> SendResult {
-        match &self.queue {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => match queue.find(user) {
-                Some(idx) => {
-                    self.send_msg(format!("@{user} you are number {} in queue", idx + 1))
-                        .await
-                }
-                None => {
-                    self.send_msg(format!("@{user}: You're not currently queued"))
-                        .await
-                }
-            },
+    /// Calls `Channel::flush_journal` for every channel
+    pub fn flush_journal(&mut self) {
+        for channel in self.channels.values_mut() {
+            channel.flush_journal();
         }
     }
 
-    pub async fn length(&self) -> SendResult {
-        match &self.queue {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => {
-                self.send_msg(format!("There are {} people in queue", queue.len()))
-                    .await
+    pub async fn recv_msg(&mut self) -> Option<Message> {
+        loop {
+            let Some(autosave) = self.autosave.as_mut() else {
+                return self.chat.recv_msg().await;
+            };
+            tokio::select! {
+                msg = self.chat.recv_msg() => return msg,
+                Some(()) = autosave.recv() => {}
             }
+            self.autosave();
         }
     }
 
-    pub async fn list(&self) -> SendResult {
-        fn format_list<T: AsRef<str> + std::fmt::Display>(l: &[T]) -> String {
-            l.iter()
-                .enumerate()
-                .map(|(i, s)| format!("[{}. {}]", i + 1, s))
-                .collect::<Vec<_>>()
-                .join(", ")
-        }
+    /// Sends `msg` as a reply threaded under the message with id `reply_to`,
+    /// split like [`Bot::send_msg`]
+    pub async fn reply(
+        &self,
+        channel: &str,
+        reply_to: &str,
+        msg: String,
+        priority: Priority,
+    ) -> SendResult {
+        send_split(&msg, |part| {
+            self.chat.reply_msg(channel, reply_to, part, priority)
+        })
+        .await
+    }
 
-        match &self.queue {
-            None => Ok(self.send_msg(messages::QUEUE_NOT_LOADED.into()).await?),
-            Some(queue) => {
-                const MAX_LIST: usize = 5;
-                let l = queue.list();
-                println!("Logging full list: {l:?}");
-                match l.len() {
-                    0 => self.send_msg(messages::QUEUE_EMPTY.into()).await,
-                    1..=MAX_LIST => {
-                        self.send_msg(format!("People in queue: {}", format_list(l)))
-                            .await
-                    }
-                    n => {
-                        self.send_msg(format!(
-                            "People in queue (first {MAX_LIST} out of {n}): {}",
-                            format_list(&l[..MAX_LIST])
-                        ))
-                        .await
-                    }
-                }
+    /// Whispers `msg` to `user`, split like [`Bot::send_msg`]
+    pub async fn whisper(&self, user: &str, msg: String) -> SendResult {
+        send_split(&msg, |part| self.chat.whisper(user, part)).await
+    }
+
+    /// Sends `msg`, split into several messages if it is too long for chat
+    pub async fn send_msg(&self, channel: &str, msg: String, priority: Priority) -> SendResult {
+        send_split(&msg, |part| self.chat.send_msg(channel, part, priority)).await
+    }
+}
+
+/// Sends `msg` with `send`, split into parts short enough for chat.
+/// Once the client has been closed the remaining parts are dropped.
+async fn send_split<F, Fut>(msg: &str, mut send: F) -> SendResult
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = SendResult>,
+{
+    for part in split_message(msg) {
+        match send(part).await {
+            Err(SendError::ClientClosed) => {
+                warn!("Client has been closed, will not be sent");
+                return Ok(());
             }
+            other => other?,
         }
     }
+    Ok(())
 }
@@ -0,0 +1,280 @@
+//! Rate limited send queue, keeping the bot within Twitch's chat limits
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use super::chat::MAX_MESSAGE_LEN;
+
+#[cfg(test)]
+mod tests;
+
+/// Messages waiting beyond this are dropped, low priority ones first
+const MAX_PENDING: usize = 10;
+/// Separates replies coalesced into one message
+const SEPARATOR: &str = " | ";
+
+/// How important a reply is when chat is busy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Replies to commands anyone can use, dropped first
+    Low,
+    /// Replies to mod commands and announcements
+    High,
+}
+
+/// At most `messages` in any window of length `per`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+impl RateLimit {
+    /// Accounts that are neither mod nor verified
+    pub const REGULAR: Self = Self::per_30s(20);
+    /// Channels where the bot is a mod or the broadcaster
+    pub const MODERATOR: Self = Self::per_30s(100);
+    /// Verified bots, across all channels
+    pub const VERIFIED: Self = Self::per_30s(7500);
+
+    /// Whispers, on top of [`RateLimit::WHISPERS_PER_MINUTE`]
+    pub const WHISPERS_PER_SECOND: Self = Self {
+        messages: 3,
+        per: Duration::from_secs(1),
+    };
+    pub const WHISPERS_PER_MINUTE: Self = Self {
+        messages: 100,
+        per: Duration::from_secs(60),
+    };
+
+    const fn per_30s(messages: u32) -> Self {
+        Self {
+            messages,
+            per: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Remembers the sends of the last `per`, so no window of that length
+/// ever holds more than `messages` of them
+struct SlidingWindow {
+    limit: RateLimit,
+    /// Oldest first
+    sent: VecDeque<Instant>,
+}
+
+impl SlidingWindow {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Time until a message can be sent
+    fn wait(&mut self, now: Instant) -> Duration {
+        while let Some(&oldest) = self.sent.front() {
+            if oldest + self.limit.per > now {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        if self.sent.len() < self.limit.messages as usize {
+            return Duration::ZERO;
+        }
+        // The oldest send has to leave the window first
+        let oldest = self.sent[self.sent.len() - self.limit.messages as usize];
+        (oldest + self.limit.per).saturating_duration_since(now)
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        if self.wait(now).is_zero() {
+            self.sent.push_back(now);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Paces whispers, which are limited separately from chat messages
+pub struct WhisperLimit {
+    windows: [SlidingWindow; 2],
+}
+
+impl WhisperLimit {
+    pub fn new() -> Self {
+        Self {
+            windows: [
+                SlidingWindow::new(RateLimit::WHISPERS_PER_SECOND),
+                SlidingWindow::new(RateLimit::WHISPERS_PER_MINUTE),
+            ],
+        }
+    }
+
+    /// Time until the next whisper can be sent
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        self.windows
+            .iter_mut()
+            .map(|window| window.wait(now))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Records a whisper sent at `now`, returns `false` if it had to wait
+    pub fn take(&mut self, now: Instant) -> bool {
+        if !self.wait(now).is_zero() {
+            return false;
+        }
+        for window in &mut self.windows {
+            window.sent.push_back(now);
+        }
+        true
+    }
+}
+
+/// A message waiting to be sent
+#[derive(Debug)]
+pub struct Pending {
+    pub channel: String,
+    pub text: String,
+    pub priority: Priority,
+    /// Id of the chat message this is a threaded reply to
+    pub reply_to: Option<String>,
+}
+
+impl Pending {
+    pub fn new(channel: &str, text: String, priority: Priority) -> Self {
+        Self {
+            channel: channel.to_owned(),
+            text,
+            priority,
+            reply_to: None,
+        }
+    }
+
+    pub fn in_reply_to(self, id: &str) -> Self {
+        Self {
+            reply_to: Some(id.to_owned()),
+            ..self
+        }
+    }
+}
+
+/// Replies waiting to be sent, released as the rate limits allow
+pub struct Outbox {
+    pending: VecDeque<Pending>,
+    /// Used in channels where the bot isn't a mod, or everywhere if verified
+    regular: SlidingWindow,
+    moderator: SlidingWindow,
+    verified: bool,
+    /// Channels where the bot is a mod or the broadcaster
+    moderated: HashSet<String>,
+}
+
+impl Outbox {
+    pub fn new(verified: bool) -> Self {
+        let regular = if verified {
+            RateLimit::VERIFIED
+        } else {
+            RateLimit::REGULAR
+        };
+        Self {
+            pending: VecDeque::new(),
+            regular: SlidingWindow::new(regular),
+            moderator: SlidingWindow::new(RateLimit::MODERATOR),
+            verified,
+            moderated: HashSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Records whether the bot is a mod in `channel`, which raises its limit there
+    pub fn set_moderator(&mut self, channel: &str, moderator: bool) {
+        if moderator {
+            self.moderated.insert(channel.to_owned());
+        } else {
+            self.moderated.remove(channel);
+        }
+    }
+
+    fn window(&mut self, channel: &str) -> &mut SlidingWindow {
+        if !self.verified && self.moderated.contains(channel) {
+            &mut self.moderator
+        } else {
+            &mut self.regular
+        }
+    }
+
+    /// Queues a message, appending it to a waiting one of the same channel
+    /// and priority if both fit into a single chat message. Threaded replies
+    /// are only combined with replies to the same message.
+    pub fn push(&mut self, message: Pending) {
+        let waiting = self.pending.iter_mut().rev().find(|p| {
+            p.channel == message.channel
+                && p.priority == message.priority
+                && p.reply_to == message.reply_to
+        });
+        if let Some(waiting) = waiting {
+            if waiting.text.len() + SEPARATOR.len() + message.text.len() <= MAX_MESSAGE_LEN {
+                waiting.text.push_str(SEPARATOR);
+                waiting.text.push_str(&message.text);
+                return;
+            }
+        }
+        self.pending.push_back(message);
+        if self.pending.len() > MAX_PENDING {
+            let index = self
+                .pending
+                .iter()
+                .position(|p| p.priority == Priority::Low)
+                .unwrap_or(0);
+            if let Some(dropped) = self.pending.remove(index) {
+                warn!(
+                    "Chat is busy, dropping message to #{}: {}",
+                    dropped.channel, dropped.text
+                );
+            }
+        }
+    }
+
+    /// Takes the next message that can be sent right away, high priority first
+    pub fn pop(&mut self, now: Instant) -> Option<Pending> {
+        let mut ready = None;
+        for index in 0..self.pending.len() {
+            let (channel, priority) = {
+                let pending = &self.pending[index];
+                (pending.channel.clone(), pending.priority)
+            };
+            if !self.window(&channel).wait(now).is_zero() {
+                continue;
+            }
+            if priority == Priority::High {
+                ready = Some(index);
+                break;
+            }
+            ready = ready.or(Some(index));
+        }
+        let pending = self.pending.remove(ready?)?;
+        self.window(&pending.channel).take(now);
+        Some(pending)
+    }
+
+    /// Time until the next message can be sent, `None` if nothing is waiting
+    pub fn next_send(&mut self, now: Instant) -> Option<Duration> {
+        let channels: HashSet<_> = self.pending.iter().map(|p| p.channel.clone()).collect();
+        channels
+            .iter()
+            .map(|channel| self.window(channel).wait(now))
+            .min()
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use super::{Outbox, Pending, Priority, RateLimit, WhisperLimit};
+
+/// Sends everything that can be sent at `now`
+fn drain(outbox: &mut Outbox, now: Instant) -> Vec<String> {
+    std::iter::from_fn(|| outbox.pop(now))
+        .map(|pending| pending.text)
+        .collect()
+}
+
+#[test]
+fn sends_within_the_limit() {
+    let start = Instant::now();
+    let mut outbox = Outbox::new(false);
+    let limit = RateLimit::REGULAR.messages as usize;
+    for n in 0..limit {
+        outbox.push(Pending::new(
+            &format!("channel{n}"),
+            n.to_string(),
+            Priority::Low,
+        ));
+        assert_eq!(drain(&mut outbox, start), [n.to_string()]);
+    }
+
+    // The next message waits until the first sends leave the window
+    outbox.push(Pending::new("test", "late".into(), Priority::Low));
+    assert!(drain(&mut outbox, start).is_empty());
+    assert_eq!(outbox.next_send(start), Some(RateLimit::REGULAR.per));
+    let almost = start + RateLimit::REGULAR.per - Duration::from_millis(1);
+    assert!(drain(&mut outbox, almost).is_empty());
+    let later = start + RateLimit::REGULAR.per;
+    assert_eq!(drain(&mut outbox, later), ["late"]);
+    assert_eq!(outbox.next_send(later), None);
+
+    // Sends spread over the window don't allow a burst at its end either
+    let mut outbox = Outbox::new(false);
+    for n in 0..limit {
+        let at = start + Duration::from_secs(n as u64);
+        outbox.push(Pending::new(
+            &format!("channel{n}"),
+            n.to_string(),
+            Priority::Low,
+        ));
+        assert_eq!(drain(&mut outbox, at).len(), 1);
+    }
+    outbox.push(Pending::new("test", "late".into(), Priority::Low));
+    let end = start + RateLimit::REGULAR.per - Duration::from_millis(1);
+    assert!(drain(&mut outbox, end).is_empty());
+    assert_eq!(drain(&mut outbox, start + RateLimit::REGULAR.per), ["late"]);
+}
+
+#[test]
+fn moderated_channels_have_a_higher_limit() {
+    let start = Instant::now();
+    let mut outbox = Outbox::new(false);
+    outbox.set_moderator("modded", true);
+    let limit = RateLimit::MODERATOR.messages as usize;
+    for n in 0..limit {
+        outbox.push(Pending::new(
+            "modded",
+            "x".repeat(400) + &n.to_string(),
+            Priority::High,
+        ));
+        assert_eq!(drain(&mut outbox, start).len(), 1);
+    }
+    outbox.push(Pending::new(
+        "modded",
+        "one too many".into(),
+        Priority::High,
+    ));
+    assert!(drain(&mut outbox, start).is_empty());
+    // The regular limit is kept separately
+    outbox.push(Pending::new("other", "hello".into(), Priority::Low));
+    assert_eq!(drain(&mut outbox, start), ["hello"]);
+}
+
+#[test]
+fn coalesces_and_drops_low_priority_first() {
+    let start = Instant::now();
+    let mut outbox = Outbox::new(false);
+    for n in 0..RateLimit::REGULAR.messages {
+        outbox.push(Pending::new(
+            &n.to_string(),
+            "used up".into(),
+            Priority::Low,
+        ));
+        drain(&mut outbox, start);
+    }
+
+    outbox.push(Pending::new("test", "@a: You are #1".into(), Priority::Low));
+    outbox.push(Pending::new("test", "@b: You are #2".into(), Priority::Low));
+    outbox.push(Pending::new("test", "Next up: a".into(), Priority::High));
+    assert_eq!(outbox.len(), 2);
+
+    let long = "x".repeat(400);
+    for _ in 0..10 {
+        outbox.push(Pending::new("test", long.clone(), Priority::High));
+    }
+    assert_eq!(outbox.len(), 10);
+    let later = start + Duration::from_secs(30);
+    let sent = drain(&mut outbox, later);
+    assert_eq!(sent[0], format!("Next up: a | {long}"));
+    assert!(sent.iter().all(|text| !text.starts_with("@a")));
+}
+
+#[test]
+fn verified_bots_share_one_large_limit() {
+    let start = Instant::now();
+    let mut outbox = Outbox::new(true);
+    for n in 0..1000 {
+        outbox.push(Pending::new(
+            "test",
+            "x".repeat(400) + &n.to_string(),
+            Priority::Low,
+        ));
+        assert_eq!(drain(&mut outbox, start).len(), 1);
+    }
+}
+
+#[test]
+fn replies_are_only_combined_within_their_thread() {
+    let start = Instant::now();
+    let mut outbox = Outbox::new(false);
+    for n in 0..RateLimit::REGULAR.messages {
+        outbox.push(Pending::new(
+            &n.to_string(),
+            "used up".into(),
+            Priority::Low,
+        ));
+        drain(&mut outbox, start);
+    }
+    outbox.push(Pending::new("test", "You are #1".into(), Priority::Low).in_reply_to("1"));
+    outbox.push(Pending::new("test", "You left".into(), Priority::Low).in_reply_to("1"));
+    outbox.push(Pending::new("test", "You are #2".into(), Priority::Low).in_reply_to("2"));
+    assert_eq!(outbox.len(), 2);
+    let later = start + Duration::from_secs(30);
+    let sent: Vec<_> = std::iter::from_fn(|| outbox.pop(later))
+        .map(|pending| (pending.reply_to.unwrap(), pending.text))
+        .collect();
+    assert_eq!(
+        sent,
+        [
+            ("1".to_owned(), "You are #1 | You left".to_owned()),
+            ("2".to_owned(), "You are #2".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn whispers_are_limited_per_second_and_minute() {
+    let start = Instant::now();
+    let mut limit = WhisperLimit::new();
+    for _ in 0..RateLimit::WHISPERS_PER_SECOND.messages {
+        assert!(limit.take(start));
+    }
+    assert!(!limit.take(start));
+    assert_eq!(limit.wait(start), Duration::from_secs(1));
+
+    let mut now = start;
+    let mut sent = RateLimit::WHISPERS_PER_SECOND.messages;
+    while sent < RateLimit::WHISPERS_PER_MINUTE.messages {
+        now += Duration::from_secs(1);
+        for _ in 0..RateLimit::WHISPERS_PER_SECOND.messages {
+            if sent < RateLimit::WHISPERS_PER_MINUTE.messages {
+                assert!(limit.take(now));
+                sent += 1;
+            }
+        }
+    }
+    // The per minute limit holds back the next one until the first ones expire
+    now += Duration::from_secs(1);
+    assert_eq!(limit.wait(now), start + Duration::from_secs(60) - now);
+    assert!(!limit.take(now));
+    assert!(limit.take(start + Duration::from_secs(60)));
+}
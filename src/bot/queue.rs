@@ -1,77 +1,221 @@
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::fs;
-use std::io::ErrorKind;
-
-pub const DATA_DIR: &str = "data/";
 
 pub enum PushError {
     Present(usize),
     Played,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A single mutation of a queue, as written to the journal
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    Push {
+        user: String,
+    },
+    Shift,
+    Remove {
+        user: String,
+    },
+    Clear,
+    Reset,
+    SetName {
+        user: String,
+        name: String,
+    },
+    Open,
+    Close,
+    /// Puts a user back at the front and removes them from the player history
+    Unshift {
+        user: String,
+    },
+    Insert {
+        index: usize,
+        user: String,
+    },
+    /// Puts users back in front of the queue, skipping those already queued
+    Prepend {
+        users: Vec<String>,
+    },
+    /// Adds users back to the player history
+    Unreset {
+        users: Vec<String>,
+    },
+}
+
+/// A recorded mutation together with the operation reverting it
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub op: Op,
+    pub inverse: Option<Op>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub op: Op,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Queue {
     pub name: String,
     pub is_open: bool,
     list: Vec<String>,
     played: HashSet<String>,
-    pub names: HashMap<String, String>,
+    names: HashMap<String, String>,
+    /// Sequence number of the last recorded mutation
+    seq: u64,
+    /// Mutations not yet handed to the store
+    #[serde(skip)]
+    journal: Vec<JournalEntry>,
+    /// Mutations not yet taken for the undo history
+    #[serde(skip)]
+    changes: Vec<Change>,
 }
 
 impl Queue {
-    fn slugify(name: &str) -> String {
+    pub fn slugify(name: &str) -> String {
         name.to_lowercase().replace(' ', "-")
     }
 
-    fn filename(&self) -> String {
-        format!("{}{}.json", DATA_DIR, Self::slugify(&self.name))
+    /// Whether `name` can be stored. Slugs are used as file names, so they
+    /// may only contain `a-z`, `0-9`, `-` and `_`, which rules out `/` and `..`.
+    pub fn is_valid_name(name: &str) -> bool {
+        let slug = Self::slugify(name);
+        !slug.is_empty()
+            && slug
+                .bytes()
+                .all(|byte| matches!(byte, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+    }
+
+    pub fn slug(&self) -> String {
+        Self::slugify(&self.name)
     }
 
     pub fn new(name: &str) -> Self {
-        let new = Self {
+        Self {
             is_open: false,
             name: name.to_owned(),
             list: Vec::new(),
             played: HashSet::new(),
             names: HashMap::new(),
-        };
-        fs::File::create(new.filename()).unwrap();
-        new.save();
-        new
-    }
-
-    pub fn load(name: &str) -> Option<Self> {
-        Some(
-            serde_json::from_str(&match fs::read_to_string(format!(
-                "data/{}.json",
-                Self::slugify(name)
-            )) {
-                Ok(list) => list,
-                Err(error) => match error.kind() {
-                    ErrorKind::NotFound => return None,
-                    _ => panic!("Error loading queue file: {error}"),
-                },
-            })
-            .unwrap(),
-        )
-    }
-
-    pub fn save(&self) {
-        let filename = self.filename();
-        println!("Trying to save queue {} to {}", self.name, filename);
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(filename)
-            .unwrap();
-        serde_json::to_writer(file, self).unwrap();
+            seq: 0,
+            journal: Vec::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn from_parts(
+        name: String,
+        is_open: bool,
+        list: Vec<String>,
+        played: HashSet<String>,
+        names: HashMap<String, String>,
+    ) -> Self {
+        Self {
+            name,
+            is_open,
+            list,
+            played,
+            names,
+            seq: 0,
+            journal: Vec::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, op: Op, inverse: Option<Op>) {
+        self.seq += 1;
+        self.journal.push(JournalEntry {
+            seq: self.seq,
+            op: op.clone(),
+        });
+        self.changes.push(Change { op, inverse });
+    }
+
+    /// Takes the mutations recorded since the last call
+    pub fn take_journal(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.journal)
+    }
+
+    /// Takes the mutations, with their inverses, recorded since the last call
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Performs `op`, returns `false` if it had no effect
+    pub fn apply(&mut self, op: &Op) -> bool {
+        match op {
+            Op::Push { user } => self.push(user).is_ok(),
+            Op::Shift => self.shift().is_some(),
+            Op::Remove { user } => self.remove(user).is_ok(),
+            Op::Clear => {
+                self.clear();
+                true
+            }
+            Op::Reset => {
+                self.reset();
+                true
+            }
+            Op::SetName { user, name } => {
+                self.set_name(user, name);
+                true
+            }
+            Op::Open => self.open().is_ok(),
+            Op::Close => self.close().is_ok(),
+            Op::Unshift { user } => {
+                if self.find(user).is_some() {
+                    return false;
+                }
+                self.list.insert(0, user.clone());
+                self.played.remove(user);
+                self.record(op.clone(), Some(Op::Shift));
+                true
+            }
+            Op::Insert { index, user } => {
+                if self.find(user).is_some() {
+                    return false;
+                }
+                self.list
+                    .insert((*index).min(self.list.len()), user.clone());
+                self.record(op.clone(), Some(Op::Remove { user: user.clone() }));
+                true
+            }
+            Op::Prepend { users } => {
+                let users: Vec<_> = users
+                    .iter()
+                    .filter(|user| self.find(user).is_none())
+                    .cloned()
+                    .collect();
+                if users.is_empty() {
+                    return false;
+                }
+                self.list.splice(0..0, users.iter().cloned());
+                self.record(Op::Prepend { users }, None);
+                true
+            }
+            Op::Unreset { users } => {
+                self.played.extend(users.iter().cloned());
+                self.record(op.clone(), None);
+                true
+            }
+        }
     }
-}
 
-impl Drop for Queue {
-    fn drop(&mut self) {
-        self.save();
+    /// Applies journal entries newer than the last recorded mutation,
+    /// entries already contained in this state are skipped
+    pub fn replay(&mut self, entries: impl IntoIterator<Item = JournalEntry>) {
+        for entry in entries {
+            if entry.seq <= self.seq {
+                continue;
+            }
+            self.apply(&entry.op);
+            self.seq = entry.seq;
+        }
+        self.journal.clear();
+        self.changes.clear();
     }
 }
 
@@ -88,26 +232,60 @@ impl Queue {
         self.list.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
     pub fn list(&self) -> &[String] {
         &self.list
     }
 
+    #[cfg(feature = "sqlite")]
+    pub fn played(&self) -> &HashSet<String> {
+        &self.played
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn names(&self) -> &HashMap<String, String> {
+        &self.names
+    }
+
+    pub fn player_name(&self, user: &str) -> Option<&String> {
+        self.names.get(user)
+    }
+
+    pub fn set_name(&mut self, user: &str, name: &str) {
+        self.names.insert(user.to_owned(), name.to_owned());
+        self.record(
+            Op::SetName {
+                user: user.to_owned(),
+                name: name.to_owned(),
+            },
+            None,
+        );
+    }
+
     pub fn clear(&mut self) {
-        self.list.clear();
+        let users = std::mem::take(&mut self.list);
+        self.record(Op::Clear, Some(Op::Prepend { users }));
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn open(&mut self) -> Result<(), ()> {
         if self.is_open {
             Err(())
         } else {
             self.is_open = true;
+            self.record(Op::Open, Some(Op::Close));
             Ok(())
         }
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn close(&mut self) -> Result<(), ()> {
         if self.is_open {
             self.is_open = false;
+            self.record(Op::Close, Some(Op::Open));
             Ok(())
         } else {
             Err(())
@@ -123,6 +301,8 @@ impl Queue {
             Some(idx) => Err(PushError::Present(idx)),
             None => {
                 self.list.push(user.to_owned());
+                let user = user.to_owned();
+                self.record(Op::Push { user: user.clone() }, Some(Op::Remove { user }));
                 Ok(self.list.len() - 1)
             }
         }
@@ -134,19 +314,26 @@ impl Queue {
         } else {
             let user = self.list.remove(0);
             self.played.insert(user.clone());
+            self.record(Op::Shift, Some(Op::Unshift { user: user.clone() }));
             Some(user)
         }
     }
 
     pub fn reset(&mut self) {
-        self.played = HashSet::new();
+        let users = std::mem::take(&mut self.played).into_iter().collect();
+        self.record(Op::Reset, Some(Op::Unreset { users }));
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn remove(&mut self, user: &str) -> Result<(), ()> {
         match self.find(user) {
             None => Err(()),
             Some(idx) => {
-                self.list.remove(idx);
+                let user = self.list.remove(idx);
+                self.record(
+                    Op::Remove { user: user.clone() },
+                    Some(Op::Insert { index: idx, user }),
+                );
                 Ok(())
             }
         }
@@ -0,0 +1,623 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info, warn};
+
+use super::queue::{JournalEntry, Queue};
+
+mod schema;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(test)]
+mod tests;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+pub const DATA_DIR: &str = "data/";
+
+/// Number of journal entries after which the journal is compacted into the snapshot
+const COMPACT_AFTER: usize = 100;
+
+const BACKUP_DIR: &str = "backups";
+
+/// File in the data dir listing the channels joined with !addme
+const CHANNELS_FILE: &str = "channels.json";
+/// File in the data dir holding the command aliases of every channel
+const ALIASES_FILE: &str = "aliases.json";
+/// Default file in the data dir holding the refreshing login token
+pub const TOKEN_FILE: &str = "token.json";
+
+/// Data dir of a channel inside `dir`, every channel keeps its queues separately
+pub fn channel_dir(dir: impl AsRef<Path>, channel: &str) -> PathBuf {
+    dir.as_ref().join(channel)
+}
+
+/// Moves the queues that single channel versions stored directly in `dir`
+/// into the data dir of `channel`, unless that channel has a data dir already.
+/// Only queue snapshots, journals and backups are moved, `token_file` and
+/// the other files of the data dir stay where they are.
+pub fn adopt_legacy_data(
+    dir: impl AsRef<Path>,
+    channel: &str,
+    token_file: Option<&Path>,
+) -> std::io::Result<()> {
+    let dir = dir.as_ref();
+    let target = dir.join(channel);
+    if !dir.is_dir() || target.exists() {
+        return Ok(());
+    }
+    let token_file = token_file.and_then(|path| fs::canonicalize(path).ok());
+    let mut legacy = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if [CHANNELS_FILE, ALIASES_FILE, TOKEN_FILE].contains(&name.to_str().unwrap_or_default())
+            || (token_file.is_some() && fs::canonicalize(entry.path()).ok() == token_file)
+        {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        let is_queue = Path::new(&name)
+            .extension()
+            .map_or(false, |ext| ext == "json" || ext == "journal");
+        if (file_type.is_file() && is_queue) || (file_type.is_dir() && name == BACKUP_DIR) {
+            legacy.push(name);
+        }
+    }
+    if legacy.is_empty() {
+        return Ok(());
+    }
+    info!("Moving queues in {} to channel {channel}", dir.display());
+    fs::create_dir_all(&target)?;
+    for name in legacy {
+        fs::rename(dir.join(&name), target.join(&name))?;
+    }
+    Ok(())
+}
+
+/// Channels joined at runtime, persisted so they are rejoined on startup
+#[derive(Debug, Default)]
+pub struct ChannelList {
+    /// `None` keeps the list in memory only
+    path: Option<PathBuf>,
+    channels: Vec<String>,
+}
+
+impl ChannelList {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Reads the list kept in `dir`, which is empty if it hasn't been saved yet
+    pub fn open(dir: impl AsRef<Path>) -> StoreResult<Self> {
+        let path = dir.as_ref().join(CHANNELS_FILE);
+        let channels = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            channels,
+        })
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    pub fn contains(&self, channel: &str) -> bool {
+        self.channels.iter().any(|c| c == channel)
+    }
+
+    pub fn add(&mut self, channel: &str) -> StoreResult<()> {
+        if !self.contains(channel) {
+            self.channels.push(channel.to_owned());
+        }
+        self.save()
+    }
+
+    pub fn remove(&mut self, channel: &str) -> StoreResult<()> {
+        self.channels.retain(|c| c != channel);
+        self.save()
+    }
+
+    fn save(&self) -> StoreResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        debug!("Saving channel list to {}", path.display());
+        write_json(path, &self.channels)
+    }
+}
+
+/// Command aliases defined with !alias, kept per channel
+#[derive(Debug, Default)]
+pub struct Aliases {
+    /// `None` keeps the aliases in memory only
+    path: Option<PathBuf>,
+    /// Commands by alias, by channel
+    channels: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Aliases {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Reads the aliases kept in `dir`, which are empty if none were saved yet
+    pub fn open(dir: impl AsRef<Path>) -> StoreResult<Self> {
+        let mut aliases = Self {
+            path: Some(dir.as_ref().join(ALIASES_FILE)),
+            channels: BTreeMap::new(),
+        };
+        aliases.reload()?;
+        Ok(aliases)
+    }
+
+    /// Reads the aliases again, e.g. after the file was edited by hand.
+    /// On error the current ones are kept.
+    pub fn reload(&mut self) -> StoreResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        self.channels = match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(error.into()),
+        };
+        Ok(())
+    }
+
+    /// The command `alias` stands for in `channel`, ignoring case
+    pub fn get(&self, channel: &str, alias: &str) -> Option<&str> {
+        self.channels
+            .get(channel)?
+            .get(&alias.to_lowercase())
+            .map(String::as_str)
+    }
+
+    /// The aliases of `channel` and their commands, sorted by alias
+    pub fn list(&self, channel: &str) -> Vec<(&str, &str)> {
+        self.channels.get(channel).map_or_else(Vec::new, |aliases| {
+            aliases
+                .iter()
+                .map(|(alias, command)| (alias.as_str(), command.as_str()))
+                .collect()
+        })
+    }
+
+    /// Makes `alias` run `command` in `channel`, replacing what it ran before
+    pub fn add(&mut self, channel: &str, alias: &str, command: &str) -> StoreResult<()> {
+        self.channels
+            .entry(channel.to_owned())
+            .or_default()
+            .insert(alias.to_lowercase(), command.to_owned());
+        self.save()
+    }
+
+    /// Returns `false` if `channel` has no such alias
+    pub fn remove(&mut self, channel: &str, alias: &str) -> StoreResult<bool> {
+        let Some(aliases) = self.channels.get_mut(channel) else {
+            return Ok(false);
+        };
+        if aliases.remove(&alias.to_lowercase()).is_none() {
+            return Ok(false);
+        }
+        if aliases.is_empty() {
+            self.channels.remove(channel);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> StoreResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        debug!("Saving aliases to {}", path.display());
+        write_json(path, &self.channels)
+    }
+}
+
+/// Replaces the file at `path` with `value`, without leaving a partially
+/// written file behind on a crash
+fn write_json(path: &Path, value: &impl serde::Serialize) -> StoreResult<()> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).create(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp)?;
+    serde_json::to_writer_pretty(&mut file, value)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// A stored copy of a queue, identified by when it was taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Backup {
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u64,
+}
+
+impl Backup {
+    fn now() -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        }
+    }
+
+    /// Time elapsed since the backup was taken
+    pub fn age(&self) -> std::time::Duration {
+        let taken = UNIX_EPOCH + std::time::Duration::from_millis(self.timestamp);
+        SystemTime::now().duration_since(taken).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    /// Unknown or invalid snapshot format version
+    Version(Option<u64>),
+    /// A queue name that can't be used as a file name
+    InvalidName(String),
+    #[cfg(feature = "sqlite")]
+    Database(rusqlite::Error),
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Format(error)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::Database(error)
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "I/O error: {e}"),
+            Self::Format(e) => write!(fmt, "Invalid queue data: {e}"),
+            Self::Version(Some(v)) => write!(
+                fmt,
+                "Queue format version {v} is newer than the supported version {}",
+                schema::VERSION
+            ),
+            Self::Version(None) => write!(fmt, "Invalid queue format version"),
+            Self::InvalidName(name) => write!(fmt, "Invalid queue name {name}"),
+            #[cfg(feature = "sqlite")]
+            Self::Database(e) => write!(fmt, "Database error: {e}"),
+        }
+    }
+}
+
+/// Persistence backend for queues. Queues are addressed by their slug,
+/// see [`Queue::slugify`].
+pub trait QueueStore: Send {
+    fn load(&self, name: &str) -> StoreResult<Option<Queue>>;
+    fn save(&mut self, queue: &Queue) -> StoreResult<()>;
+    /// Returns the slugs of all stored queues
+    fn list(&self) -> StoreResult<Vec<String>>;
+    /// Returns `false` if the queue didn't exist
+    fn delete(&mut self, name: &str) -> StoreResult<bool>;
+
+    /// Records mutations made since the last `save` or `append`.
+    ///
+    /// The default implementation applies them to the stored queue and saves it.
+    fn append(&mut self, name: &str, entries: &[JournalEntry]) -> StoreResult<()> {
+        if let Some(mut queue) = self.load(name)? {
+            queue.replay(entries.iter().cloned());
+            self.save(&queue)?;
+        }
+        Ok(())
+    }
+
+    /// Stores a copy of `queue`, keeping only the newest `keep` backups of it
+    fn backup(&mut self, queue: &Queue, keep: usize) -> StoreResult<()>;
+    /// Returns the backups of a queue, newest first
+    fn backups(&self, name: &str) -> StoreResult<Vec<Backup>>;
+    fn load_backup(&self, name: &str, backup: Backup) -> StoreResult<Option<Queue>>;
+}
+
+/// Stores every queue as a JSON snapshot inside a directory.
+///
+/// Mutations are appended to a per-queue journal which is replayed on load
+/// and compacted into a new snapshot once it grows too long. Snapshots are
+/// written to a temporary file and renamed, so a crash never leaves a
+/// partially written queue behind.
+pub struct JsonFileStore {
+    dir: PathBuf,
+    journal_len: HashMap<String, usize>,
+}
+
+impl JsonFileStore {
+    pub fn new(dir: impl AsRef<Path>) -> StoreResult<Self> {
+        let dir = dir.as_ref().to_owned();
+        debug!("Creating data dir {}", dir.display());
+        fs::DirBuilder::new().recursive(true).create(&dir)?;
+        Ok(Self {
+            dir,
+            journal_len: HashMap::new(),
+        })
+    }
+
+    /// The slug of `name`, refusing names that would lead outside the data dir
+    fn slug(name: &str) -> StoreResult<String> {
+        if Queue::is_valid_name(name) {
+            Ok(Queue::slugify(name))
+        } else {
+            Err(StoreError::InvalidName(name.to_owned()))
+        }
+    }
+
+    fn path(&self, name: &str) -> StoreResult<PathBuf> {
+        Ok(self.dir.join(format!("{}.json", Self::slug(name)?)))
+    }
+
+    fn journal_path(&self, name: &str) -> StoreResult<PathBuf> {
+        Ok(self.dir.join(format!("{}.journal", Self::slug(name)?)))
+    }
+
+    fn backup_path(&self, name: &str, backup: Backup) -> StoreResult<PathBuf> {
+        Ok(self.dir.join(BACKUP_DIR).join(format!(
+            "{}.{}.json",
+            Self::slug(name)?,
+            backup.timestamp
+        )))
+    }
+
+    /// Reads the journal of a queue. A crash can leave its last line torn,
+    /// which is cut off so entries appended later don't get glued to it.
+    fn read_journal(&self, name: &str) -> StoreResult<Vec<JournalEntry>> {
+        let path = self.journal_path(name)?;
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+        let mut entries = Vec::new();
+        // Length of the complete lines
+        let mut complete = 0;
+        for line in data.split_inclusive(|&byte| byte == b'\n') {
+            let Some(json) = line.strip_suffix(b"\n") else {
+                break;
+            };
+            complete += line.len();
+            match serde_json::from_slice(json) {
+                Ok(entry) => entries.push(entry),
+                // Left behind by earlier versions, which didn't cut off torn lines
+                Err(e) => warn!("Ignoring unreadable journal entry for queue {name}: {e}"),
+            }
+        }
+        if complete < data.len() {
+            warn!("Cutting off torn last line of the journal of queue {name}");
+            let file = fs::OpenOptions::new().write(true).open(&path)?;
+            file.set_len(complete as u64)?;
+            file.sync_all()?;
+        }
+        Ok(entries)
+    }
+}
+
+impl QueueStore for JsonFileStore {
+    fn load(&self, name: &str) -> StoreResult<Option<Queue>> {
+        let mut queue: Queue = match fs::read_to_string(self.path(name)?) {
+            Ok(data) => schema::decode(&data)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let entries = self.read_journal(name)?;
+        if !entries.is_empty() {
+            info!(
+                "Replaying {} journal entries for queue {name}",
+                entries.len()
+            );
+            queue.replay(entries);
+        }
+        Ok(Some(queue))
+    }
+
+    fn save(&mut self, queue: &Queue) -> StoreResult<()> {
+        let path = self.path(&queue.name)?;
+        let tmp = path.with_extension("json.tmp");
+        debug!("Saving queue {} to {}", queue.name, path.display());
+        let mut file = fs::File::create(&tmp)?;
+        schema::encode(queue, &mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        // Entries left behind by a crash here are skipped on replay by their sequence number
+        match fs::remove_file(self.journal_path(&queue.name)?) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        self.journal_len.insert(queue.slug(), 0);
+        Ok(())
+    }
+
+    fn append(&mut self, name: &str, entries: &[JournalEntry]) -> StoreResult<()> {
+        let slug = Self::slug(name)?;
+        // Reading the journal first also cuts off a line torn by a crash
+        let written = match self.journal_len.get(&slug) {
+            Some(len) => *len,
+            None => self.read_journal(name)?.len(),
+        };
+        let path = self.journal_path(name)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut data, entry)?;
+            data.push(b'\n');
+        }
+        file.write_all(&data)?;
+        file.sync_data()?;
+
+        let len = written + entries.len();
+        self.journal_len.insert(slug, len);
+        if len >= COMPACT_AFTER {
+            debug!("Compacting journal of queue {name}");
+            if let Some(queue) = self.load(name)? {
+                self.save(&queue)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> StoreResult<Vec<String>> {
+        let mut slugs = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                if let Some(stem) = path.file_stem() {
+                    slugs.push(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+        slugs.sort();
+        Ok(slugs)
+    }
+
+    fn delete(&mut self, name: &str) -> StoreResult<bool> {
+        match fs::remove_file(self.journal_path(name)?) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        self.journal_len.remove(&Queue::slugify(name));
+        match fs::remove_file(self.path(name)?) {
+            Ok(()) => Ok(true),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn backup(&mut self, queue: &Queue, keep: usize) -> StoreResult<()> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .create(self.dir.join(BACKUP_DIR))?;
+        let path = self.backup_path(&queue.name, Backup::now())?;
+        debug!("Backing up queue {} to {}", queue.name, path.display());
+        let mut file = fs::File::create(path)?;
+        schema::encode(queue, &mut file)?;
+        file.sync_all()?;
+
+        for old in self.backups(&queue.name)?.into_iter().skip(keep) {
+            debug!("Removing old backup {old:?} of queue {}", queue.name);
+            fs::remove_file(self.backup_path(&queue.name, old)?)?;
+        }
+        Ok(())
+    }
+
+    fn backups(&self, name: &str) -> StoreResult<Vec<Backup>> {
+        let slug = Self::slug(name)?;
+        let entries = match fs::read_dir(self.dir.join(BACKUP_DIR)) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+        let mut backups = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let backup = file_name
+                .to_str()
+                .and_then(|file_name| file_name.strip_suffix(".json"))
+                .and_then(|file_name| file_name.rsplit_once('.'))
+                .filter(|(backup_slug, _)| *backup_slug == slug)
+                .and_then(|(_, timestamp)| timestamp.parse().ok());
+            if let Some(timestamp) = backup {
+                backups.push(Backup { timestamp });
+            }
+        }
+        backups.sort_by(|a, b| b.cmp(a));
+        Ok(backups)
+    }
+
+    fn load_backup(&self, name: &str, backup: Backup) -> StoreResult<Option<Queue>> {
+        match fs::read_to_string(self.backup_path(name, backup)?) {
+            Ok(data) => Ok(Some(schema::decode(&data)?)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Keeps queues in memory only, nothing survives the process
+#[derive(Default)]
+pub struct MemoryStore {
+    queues: HashMap<String, Queue>,
+    /// Newest first
+    backups: HashMap<String, Vec<(Backup, Queue)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl QueueStore for MemoryStore {
+    fn load(&self, name: &str) -> StoreResult<Option<Queue>> {
+        Ok(self.queues.get(&Queue::slugify(name)).cloned())
+    }
+
+    fn save(&mut self, queue: &Queue) -> StoreResult<()> {
+        self.queues.insert(queue.slug(), queue.clone());
+        Ok(())
+    }
+
+    fn list(&self) -> StoreResult<Vec<String>> {
+        let mut slugs: Vec<_> = self.queues.keys().cloned().collect();
+        slugs.sort();
+        Ok(slugs)
+    }
+
+    fn delete(&mut self, name: &str) -> StoreResult<bool> {
+        Ok(self.queues.remove(&Queue::slugify(name)).is_some())
+    }
+
+    fn backup(&mut self, queue: &Queue, keep: usize) -> StoreResult<()> {
+        let backups = self.backups.entry(queue.slug()).or_default();
+        backups.insert(0, (Backup::now(), queue.clone()));
+        backups.truncate(keep);
+        Ok(())
+    }
+
+    fn backups(&self, name: &str) -> StoreResult<Vec<Backup>> {
+        Ok(self
+            .backups
+            .get(&Queue::slugify(name))
+            .map(|backups| backups.iter().map(|(backup, _)| *backup).collect())
+            .unwrap_or_default())
+    }
+
+    fn load_backup(&self, name: &str, backup: Backup) -> StoreResult<Option<Queue>> {
+        Ok(self.backups.get(&Queue::slugify(name)).and_then(|backups| {
+            backups
+                .iter()
+                .find(|(b, _)| *b == backup)
+                .map(|(_, queue)| queue.clone())
+        }))
+    }
+}
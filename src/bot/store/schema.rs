@@ -0,0 +1,47 @@
+//! On-disk format of JSON queue snapshots.
+//!
+//! Every snapshot carries a `version` field, files written before versioning
+//! was introduced are treated as version 0. When loading, the migrations are
+//! applied in order until the data matches [`VERSION`]. To change the format,
+//! bump [`VERSION`] and append a migration converting the previous version.
+
+use serde_json::{Map, Value};
+
+use super::{StoreError, StoreResult};
+use crate::bot::queue::Queue;
+
+pub const VERSION: u64 = 1;
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// `MIGRATIONS[n]` upgrades a version `n` snapshot to version `n + 1`
+const MIGRATIONS: [Migration; VERSION as usize] = [v0_to_v1];
+
+/// Adds the journal sequence number
+fn v0_to_v1(queue: &mut Map<String, Value>) {
+    queue.entry("seq").or_insert(Value::from(0));
+}
+
+pub fn decode(data: &str) -> StoreResult<Queue> {
+    let mut value: Map<String, Value> = serde_json::from_str(data)?;
+    let version = match value.remove("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or(StoreError::Version(None))?,
+    };
+    if version > VERSION {
+        return Err(StoreError::Version(Some(version)));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut value);
+    }
+    Ok(serde_json::from_value(Value::Object(value))?)
+}
+
+pub fn encode(queue: &Queue, writer: impl std::io::Write) -> StoreResult<()> {
+    let mut value = match serde_json::to_value(queue)? {
+        Value::Object(value) => value,
+        _ => unreachable!("Queue is serialized as a map"),
+    };
+    value.insert("version".into(), VERSION.into());
+    Ok(serde_json::to_writer(writer, &value)?)
+}
@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::{debug, info};
+
+use super::{schema, Backup, QueueStore, StoreResult};
+use crate::bot::queue::Queue;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS queues (
+        id INTEGER PRIMARY KEY,
+        slug TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        is_open INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS entries (
+        queue_id INTEGER NOT NULL REFERENCES queues(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        user TEXT NOT NULL,
+        PRIMARY KEY (queue_id, position)
+    );
+    CREATE TABLE IF NOT EXISTS player_names (
+        queue_id INTEGER NOT NULL REFERENCES queues(id) ON DELETE CASCADE,
+        user TEXT NOT NULL,
+        name TEXT NOT NULL,
+        PRIMARY KEY (queue_id, user)
+    );
+    CREATE TABLE IF NOT EXISTS play_events (
+        id INTEGER PRIMARY KEY,
+        queue_id INTEGER NOT NULL REFERENCES queues(id) ON DELETE CASCADE,
+        user TEXT NOT NULL,
+        played_at INTEGER NOT NULL,
+        reset_at INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS backups (
+        id INTEGER PRIMARY KEY,
+        slug TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS play_events_active ON play_events (queue_id, user)
+        WHERE reset_at IS NULL;
+";
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Stores queues in a SQLite database.
+///
+/// The player history is kept as a log of play events: `Queue::shift` adds an
+/// event and `Queue::reset` marks the active events as reset instead of
+/// deleting them, so past plays remain queryable.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> StoreResult<Self> {
+        debug!("Opening database {}", path.as_ref().display());
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    pub fn is_empty(&self) -> StoreResult<bool> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM queues", [], |row| row.get(0))?;
+        Ok(count == 0)
+    }
+
+    /// Copies every queue from `source` that isn't already present.
+    /// Returns the number of imported queues.
+    pub fn import(&mut self, source: &dyn QueueStore) -> StoreResult<usize> {
+        let mut imported = 0;
+        for slug in source.list()? {
+            if self.load(&slug)?.is_some() {
+                debug!("Queue {slug} already imported, skipping");
+                continue;
+            }
+            if let Some(queue) = source.load(&slug)? {
+                info!("Importing queue {}", queue.name);
+                self.save(&queue)?;
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    fn queue_id(&self, slug: &str) -> StoreResult<Option<i64>> {
+        Ok(self
+            .conn
+            .query_row("SELECT id FROM queues WHERE slug = ?1", [slug], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+}
+
+impl QueueStore for SqliteStore {
+    fn load(&self, name: &str) -> StoreResult<Option<Queue>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT id, name, is_open FROM queues WHERE slug = ?1",
+                [Queue::slugify(name)],
+                |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((id, name, is_open)) = row else {
+            return Ok(None);
+        };
+
+        let list = self
+            .conn
+            .prepare("SELECT user FROM entries WHERE queue_id = ?1 ORDER BY position")?
+            .query_map([id], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        let played = self
+            .conn
+            .prepare("SELECT user FROM play_events WHERE queue_id = ?1 AND reset_at IS NULL")?
+            .query_map([id], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        let names = self
+            .conn
+            .prepare("SELECT user, name FROM player_names WHERE queue_id = ?1")?
+            .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<HashMap<String, String>, _>>()?;
+
+        Ok(Some(Queue::from_parts(name, is_open, list, played, names)))
+    }
+
+    fn save(&mut self, queue: &Queue) -> StoreResult<()> {
+        debug!("Saving queue {} to database", queue.name);
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO queues (slug, name, is_open) VALUES (?1, ?2, ?3)
+             ON CONFLICT (slug) DO UPDATE SET name = excluded.name, is_open = excluded.is_open",
+            params![queue.slug(), queue.name, queue.is_open],
+        )?;
+        let id: i64 = tx.query_row(
+            "SELECT id FROM queues WHERE slug = ?1",
+            [queue.slug()],
+            |row| row.get(0),
+        )?;
+
+        tx.execute("DELETE FROM entries WHERE queue_id = ?1", [id])?;
+        {
+            let mut insert =
+                tx.prepare("INSERT INTO entries (queue_id, position, user) VALUES (?1, ?2, ?3)")?;
+            for (position, user) in queue.list().iter().enumerate() {
+                insert.execute(params![id, position as i64, user])?;
+            }
+        }
+
+        tx.execute("DELETE FROM player_names WHERE queue_id = ?1", [id])?;
+        {
+            let mut insert =
+                tx.prepare("INSERT INTO player_names (queue_id, user, name) VALUES (?1, ?2, ?3)")?;
+            for (user, name) in queue.names() {
+                insert.execute(params![id, user, name])?;
+            }
+        }
+
+        // Only record the difference so the play history is preserved
+        let active = tx
+            .prepare("SELECT user FROM play_events WHERE queue_id = ?1 AND reset_at IS NULL")?
+            .query_map([id], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        let now = now();
+        for user in active.difference(queue.played()) {
+            tx.execute(
+                "UPDATE play_events SET reset_at = ?1
+                 WHERE queue_id = ?2 AND user = ?3 AND reset_at IS NULL",
+                params![now, id, user],
+            )?;
+        }
+        for user in queue.played().difference(&active) {
+            tx.execute(
+                "INSERT INTO play_events (queue_id, user, played_at) VALUES (?1, ?2, ?3)",
+                params![id, user, now],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list(&self) -> StoreResult<Vec<String>> {
+        Ok(self
+            .conn
+            .prepare("SELECT slug FROM queues ORDER BY slug")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?)
+    }
+
+    fn delete(&mut self, name: &str) -> StoreResult<bool> {
+        match self.queue_id(&Queue::slugify(name))? {
+            None => Ok(false),
+            Some(id) => {
+                self.conn
+                    .execute("DELETE FROM queues WHERE id = ?1", [id])?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn backup(&mut self, queue: &Queue, keep: usize) -> StoreResult<()> {
+        let mut data = Vec::new();
+        schema::encode(queue, &mut data)?;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO backups (slug, created_at, data) VALUES (?1, ?2, ?3)",
+            params![
+                queue.slug(),
+                Backup::now().timestamp as i64,
+                String::from_utf8_lossy(&data)
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM backups WHERE slug = ?1 AND id NOT IN
+             (SELECT id FROM backups WHERE slug = ?1 ORDER BY created_at DESC, id DESC LIMIT ?2)",
+            params![queue.slug(), keep as i64],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn backups(&self, name: &str) -> StoreResult<Vec<Backup>> {
+        Ok(self
+            .conn
+            .prepare(
+                "SELECT created_at FROM backups WHERE slug = ?1 ORDER BY created_at DESC, id DESC",
+            )?
+            .query_map([Queue::slugify(name)], |row| {
+                Ok(Backup {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn load_backup(&self, name: &str, backup: Backup) -> StoreResult<Option<Queue>> {
+        let data: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT data FROM backups WHERE slug = ?1 AND created_at = ?2
+                 ORDER BY id DESC LIMIT 1",
+                params![Queue::slugify(name), backup.timestamp as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        data.map(|data| schema::decode(&data)).transpose()
+    }
+}
@@ -0,0 +1,311 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+#[cfg(feature = "sqlite")]
+use super::SqliteStore;
+use super::{
+    adopt_legacy_data, schema, ChannelList, JsonFileStore, QueueStore, StoreError, BACKUP_DIR,
+    COMPACT_AFTER,
+};
+use crate::bot::queue::{JournalEntry, Op, PushError, Queue};
+use crate::testing::temp_path;
+
+/// Creates an open queue and returns it with the store it was saved in
+fn open_queue(dir: &PathBuf) -> (JsonFileStore, Queue) {
+    let mut store = JsonFileStore::new(dir).unwrap();
+    let mut queue = Queue::new("Test");
+    queue.open().unwrap();
+    queue.take_journal();
+    store.save(&queue).unwrap();
+    (store, queue)
+}
+
+/// Hands the mutations of `queue` to the store, like the bot after every command
+fn flush(store: &mut dyn QueueStore, queue: &mut Queue) {
+    let entries = queue.take_journal();
+    store.append(&queue.name, &entries).unwrap();
+}
+
+fn push(store: &mut JsonFileStore, queue: &mut Queue, user: &str) {
+    assert!(queue.push(user).is_ok());
+    flush(store, queue);
+}
+
+#[test]
+fn journal_is_replayed() {
+    let dir = temp_path("store-replay");
+    let (mut store, mut queue) = open_queue(&dir);
+    push(&mut store, &mut queue, "alice");
+    push(&mut store, &mut queue, "bob");
+    queue.shift();
+    flush(&mut store, &mut queue);
+    assert!(store.journal_path("Test").unwrap().exists());
+
+    let mut loaded = JsonFileStore::new(&dir)
+        .unwrap()
+        .load("test")
+        .unwrap()
+        .unwrap();
+    assert_eq!(loaded.list(), ["bob"]);
+    assert!(loaded.is_open);
+    assert!(loaded.push("alice").is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn torn_journal_line_is_cut_off() {
+    let dir = temp_path("store-torn");
+    let (mut store, mut queue) = open_queue(&dir);
+    push(&mut store, &mut queue, "alice");
+    // A crash while appending the next entry
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(store.journal_path("Test").unwrap())
+        .unwrap()
+        .write_all(br#"{"seq":2,"op":"pu"#)
+        .unwrap();
+
+    let mut store = JsonFileStore::new(&dir).unwrap();
+    let mut queue = store.load("Test").unwrap().unwrap();
+    assert_eq!(queue.list(), ["alice"]);
+    push(&mut store, &mut queue, "bob");
+    push(&mut store, &mut queue, "carol");
+
+    let mut store = JsonFileStore::new(&dir).unwrap();
+    let mut queue = store.load("Test").unwrap().unwrap();
+    assert_eq!(queue.list(), ["alice", "bob", "carol"]);
+    // Appending without loading first repairs the journal as well
+    push(&mut store, &mut queue, "dave");
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(store.journal_path("Test").unwrap())
+        .unwrap()
+        .write_all(br#"{"seq":5"#)
+        .unwrap();
+    let mut store = JsonFileStore::new(&dir).unwrap();
+    push(&mut store, &mut queue, "erin");
+    assert_eq!(
+        store.load("Test").unwrap().unwrap().list(),
+        ["alice", "bob", "carol", "dave", "erin"]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn long_journal_is_compacted() {
+    let dir = temp_path("store-compact");
+    let (mut store, mut queue) = open_queue(&dir);
+    for n in 0..COMPACT_AFTER - 1 {
+        push(&mut store, &mut queue, &format!("user{n}"));
+    }
+    assert!(store.journal_path("Test").unwrap().exists());
+    push(&mut store, &mut queue, "last");
+    assert!(!store.journal_path("Test").unwrap().exists());
+
+    let loaded = JsonFileStore::new(&dir)
+        .unwrap()
+        .load("Test")
+        .unwrap()
+        .unwrap();
+    assert_eq!(loaded.len(), COMPACT_AFTER);
+    assert_eq!(loaded.list().last().unwrap(), "last");
+    // The snapshot contains the compacted entries, so replaying them again changes nothing
+    push(&mut store, &mut queue, "after");
+    let loaded = store.load("Test").unwrap().unwrap();
+    assert_eq!(loaded.len(), COMPACT_AFTER + 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn channel_list_persists() {
+    let dir = temp_path("channels");
+    let mut list = ChannelList::open(&dir).unwrap();
+    assert!(list.channels().is_empty());
+    list.add("alice").unwrap();
+    list.add("bob").unwrap();
+    list.add("alice").unwrap();
+    list.remove("bob").unwrap();
+    assert_eq!(ChannelList::open(&dir).unwrap().channels(), ["alice"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unversioned_snapshot_is_migrated() {
+    let v0 = r#"{"name":"Test","is_open":true,"list":["alice","bob"],"played":["carol"],"names":{"alice":"Alice123"}}"#;
+    let mut queue = schema::decode(v0).unwrap();
+    assert_eq!(queue.name, "Test");
+    assert!(queue.is_open);
+    assert_eq!(queue.list(), ["alice", "bob"]);
+    assert_eq!(queue.player_name("alice").unwrap(), "Alice123");
+    assert!(matches!(queue.push("carol"), Err(PushError::Played)));
+
+    // The migrated queue starts counting journal entries from 0
+    let mut queue = schema::decode(v0).unwrap();
+    queue.replay([JournalEntry {
+        seq: 1,
+        op: Op::Push {
+            user: "dave".into(),
+        },
+    }]);
+    assert_eq!(queue.list(), ["alice", "bob", "dave"]);
+}
+
+#[test]
+fn snapshot_round_trips() {
+    let mut queue = Queue::new("Test");
+    queue.open().unwrap();
+    assert!(queue.push("alice").is_ok());
+    assert!(queue.push("bob").is_ok());
+    queue.shift();
+    queue.set_name("bob", "Bobby");
+
+    let mut data = Vec::new();
+    schema::encode(&queue, &mut data).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    assert_eq!(value["version"], schema::VERSION);
+
+    let mut decoded = schema::decode(std::str::from_utf8(&data).unwrap()).unwrap();
+    assert_eq!(decoded.name, "Test");
+    assert!(decoded.is_open);
+    assert_eq!(decoded.list(), ["bob"]);
+    assert_eq!(decoded.player_name("bob").unwrap(), "Bobby");
+    assert!(matches!(decoded.push("alice"), Err(PushError::Played)));
+    // Entries already contained in the snapshot aren't replayed
+    decoded.replay([JournalEntry {
+        seq: 4,
+        op: Op::Push {
+            user: "carol".into(),
+        },
+    }]);
+    assert_eq!(decoded.list(), ["bob"]);
+}
+
+#[test]
+fn newer_snapshot_is_rejected() {
+    let data = format!(
+        r#"{{"version":{},"name":"Test","is_open":false,"list":[],"played":[],"names":{{}},"seq":0}}"#,
+        schema::VERSION + 1
+    );
+    assert!(matches!(
+        schema::decode(&data),
+        Err(StoreError::Version(Some(version))) if version == schema::VERSION + 1
+    ));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn database_round_trips() {
+    let mut store = SqliteStore::open(":memory:").unwrap();
+    assert!(store.is_empty().unwrap());
+    let mut queue = Queue::new("Test");
+    queue.open().unwrap();
+    for user in ["alice", "bob", "carol"] {
+        assert!(queue.push(user).is_ok());
+    }
+    queue.set_name("bob", "Bobby");
+    store.save(&queue).unwrap();
+    queue.take_journal();
+    assert!(!store.is_empty().unwrap());
+
+    queue.shift();
+    queue.shift();
+    queue.take_changes();
+    flush(&mut store, &mut queue);
+    let mut loaded = store.load("test").unwrap().unwrap();
+    assert_eq!(loaded.list(), ["carol"]);
+    assert_eq!(loaded.player_name("bob").unwrap(), "Bobby");
+    assert!(matches!(loaded.push("alice"), Err(PushError::Played)));
+
+    // Undoing the second shift, like !undo does
+    queue.apply(&Op::Unshift { user: "bob".into() });
+    flush(&mut store, &mut queue);
+    let mut loaded = store.load("Test").unwrap().unwrap();
+    assert_eq!(loaded.list(), ["bob", "carol"]);
+    assert!(loaded.is_open);
+    assert!(matches!(loaded.push("alice"), Err(PushError::Played)));
+    assert!(matches!(loaded.push("bob"), Err(PushError::Present(0))));
+
+    queue.reset();
+    flush(&mut store, &mut queue);
+    let mut loaded = store.load("Test").unwrap().unwrap();
+    assert!(loaded.push("alice").is_ok());
+    assert_eq!(store.list().unwrap(), ["test"]);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn json_queues_are_imported() {
+    let dir = temp_path("store-import");
+    let (mut json, mut queue) = open_queue(&dir);
+    push(&mut json, &mut queue, "alice");
+    push(&mut json, &mut queue, "bob");
+    queue.shift();
+    queue.set_name("bob", "Bobby");
+    flush(&mut json, &mut queue);
+    let mut other = Queue::new("Other");
+    assert!(other.push("carol").is_ok());
+    json.save(&other).unwrap();
+
+    let mut store = SqliteStore::open(":memory:").unwrap();
+    assert_eq!(store.import(&json).unwrap(), 2);
+    assert_eq!(store.list().unwrap(), ["other", "test"]);
+    let mut loaded = store.load("Test").unwrap().unwrap();
+    assert!(loaded.is_open);
+    assert_eq!(loaded.list(), ["bob"]);
+    assert_eq!(loaded.player_name("bob").unwrap(), "Bobby");
+    assert!(matches!(loaded.push("alice"), Err(PushError::Played)));
+    assert_eq!(store.load("Other").unwrap().unwrap().list(), ["carol"]);
+
+    // Queues already in the database are left alone
+    push(&mut json, &mut queue, "dave");
+    assert_eq!(store.import(&json).unwrap(), 0);
+    assert_eq!(store.load("Test").unwrap().unwrap().list(), ["bob"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn names_leading_outside_the_dir_are_rejected() {
+    let dir = temp_path("store-names");
+    let mut store = JsonFileStore::new(dir.join("channel")).unwrap();
+    std::fs::write(dir.join("outside.json"), "{}").unwrap();
+    for name in ["../outside", "a/b", "..", "", "a\\b"] {
+        assert!(matches!(store.load(name), Err(StoreError::InvalidName(_))));
+        assert!(matches!(
+            store.delete(name),
+            Err(StoreError::InvalidName(_))
+        ));
+        assert!(matches!(
+            store.save(&Queue::new(name)),
+            Err(StoreError::InvalidName(_))
+        ));
+    }
+    assert!(dir.join("outside.json").exists());
+    assert!(Queue::is_valid_name("Mario Kart_2-final"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn only_queue_files_are_adopted() {
+    let dir = temp_path("store-legacy");
+    std::fs::create_dir_all(dir.join(BACKUP_DIR)).unwrap();
+    for file in [
+        "test.json",
+        "test.journal",
+        "channels.json",
+        "my-token.json",
+        "config.toml",
+    ] {
+        std::fs::write(dir.join(file), "{}").unwrap();
+    }
+    std::fs::write(dir.join(BACKUP_DIR).join("test.1.json"), "{}").unwrap();
+
+    adopt_legacy_data(&dir, "first", Some(&dir.join("my-token.json"))).unwrap();
+    let channel = dir.join("first");
+    for file in ["test.json", "test.journal", "backups/test.1.json"] {
+        assert!(channel.join(file).exists(), "{file} wasn't moved");
+    }
+    for file in ["channels.json", "my-token.json", "config.toml"] {
+        assert!(dir.join(file).exists(), "{file} was moved");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,270 @@
+//! Texts of the bot's replies, with placeholders like `{user}` filled in when sent
+
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::path::Path;
+
+use tracing::warn;
+
+/// Name of the file in a channel's data directory overriding its messages
+pub const FILE: &str = "messages.toml";
+
+/// Prefix of keys overriding the help text of a command, e.g. `help_join`
+const HELP_PREFIX: &str = "help_";
+
+/// The built-in English messages, every key a channel can override
+const DEFAULTS: &[(&str, &str)] = &[
+    // Queue state
+    ("queue_not_loaded", "No Queue selected"),
+    ("queue_closed", "Queue is currently closed"),
+    ("queue_close", "Queue has been closed"),
+    ("queue_close_error", "Queue is already closed"),
+    ("queue_open", "Queue is now open"),
+    ("queue_open_error", "Queue is already open"),
+    ("queue_clear", "Queue has been cleared"),
+    ("queue_empty", "The queue is currently empty"),
+    ("player_history_reset", "Player history has been reset!"),
+    ("store_error", "Couldn't access queue storage, check the logs"),
+    ("nothing_to_undo", "There is nothing to undo"),
+    ("nothing_to_redo", "There is nothing to redo"),
+    ("undid", "Undid {action}"),
+    ("redid", "Redid {action}"),
+    // Managing queues
+    ("queue_created", "Queue \"{queue}\" has been created and selected"),
+    (
+        "queue_recreated",
+        "Queue \"{queue}\" has been recreated and selected, use {prefix}restore 1 to get the previous one back",
+    ),
+    ("queue_selected", "Queue \"{queue}\" is now selected"),
+    ("queue_missing", "A queue named {queue} doesn't exist"),
+    (
+        "queue_name_invalid",
+        "Queue names may only contain letters, digits, spaces, - and _",
+    ),
+    ("queue_saved", "Queue {queue} saved"),
+    ("queue_deleted", "Queue {queue} deleted"),
+    (
+        "queue_deleted_backup",
+        "Queue {queue} deleted, {prefix}create it again and use {prefix}restore 1 to get it back",
+    ),
+    ("selected_not_deleted", "The selected queue can't be deleted"),
+    ("queues", "Saved queues: {list}"),
+    ("no_queues", "There are no saved queues"),
+    ("backups", "Backups of queue {queue}: {list}"),
+    ("backup", "[{index}. {age} ago]"),
+    ("no_backups", "There are no backups of queue {queue}"),
+    ("backup_missing", "Backup {index} of queue {queue} doesn't exist"),
+    (
+        "restored",
+        "Queue {queue} restored from backup {index}, there are {count} people in queue",
+    ),
+    // User commands
+    (
+        "already_played",
+        "@{user}: You've already played. Wait until queue reset to join again.",
+    ),
+    (
+        "already_queued",
+        "@{user}: You're already in queue at position {position}",
+    ),
+    (
+        "joined",
+        "@{user}: You've been added to the queue at position {position}",
+    ),
+    ("left", "@{user}: You've been removed from the queue"),
+    ("not_queued", "@{user}: You were not queued"),
+    ("name_changed", "@{user}: Player name changed to '{name}'"),
+    ("name_not_set", "@{user}: Player name not set"),
+    ("name", "@{user}: Current player name is '{name}'"),
+    ("position", "@{user} you are number {position} in queue"),
+    ("position_not_queued", "@{user}: You're not currently queued"),
+    ("length", "There are {count} people in queue"),
+    // Mod commands
+    ("next", "@{user} is next!"),
+    ("next_named", "@{user} ({name}) is next!"),
+    ("next_after", "@{user} is up after that."),
+    ("next_last", "That's the last one."),
+    ("list", "People in queue: {list}"),
+    ("list_first", "People in queue (first {count} out of {total}): {list}"),
+    (
+        "list_page",
+        "People in queue (page {page} of {pages}, {total} in total): {list}",
+    ),
+    ("only_page", "There is only 1 page"),
+    ("only_pages", "There are only {pages} pages"),
+    ("status", "Status: {connection} for {uptime}"),
+    ("connection_connecting", "connecting"),
+    ("connection_connected", "connected"),
+    ("connection_reconnecting", "reconnecting"),
+    ("connection_closed", "closed"),
+    ("status_reconnect", ", 1 reconnect"),
+    ("status_reconnects", ", {count} reconnects"),
+    ("status_channel", ", channel {membership}"),
+    ("membership_joining", "joining"),
+    ("membership_joined", "joined"),
+    ("membership_failed", "failed ({reason})"),
+    ("status_no_queue", ", no queue selected"),
+    ("status_open", ", queue {queue} is open with {count} people"),
+    ("status_closed", ", queue {queue} is closed with {count} people"),
+    // Channel admin
+    ("add_disabled", "Channels can't be added at runtime"),
+    (
+        "add_elsewhere",
+        "@{user}: Use {prefix}addme in #{home} to add me to your channel",
+    ),
+    ("add_present", "@{user}: I'm already in your channel"),
+    ("add_failed", "@{user}: Couldn't join your channel"),
+    (
+        "added",
+        "@{user}: I've joined your channel, use {prefix}removeme here to make me leave",
+    ),
+    ("remove_disabled", "Channels can't be removed at runtime"),
+    (
+        "remove_elsewhere",
+        "@{user}: Use {prefix}removeme in #{home} to remove me from your channel",
+    ),
+    (
+        "remove_configured",
+        "@{user}: Your channel is set in the config, ask the bot owner to remove it",
+    ),
+    ("remove_absent", "@{user}: I'm not in your channel"),
+    ("removed", "@{user}: I've left your channel"),
+    // Commands
+    (
+        "help",
+        "Use {prefix}help <command> to learn more about a command",
+    ),
+    ("commands", "Commands: "),
+    ("aliases", " (also {list})"),
+    ("unknown_command", "Unknown command {name}, see {prefix}commands"),
+    (
+        "no_aliases",
+        "There are no aliases, add one with {prefix}alias add <alias> <command>",
+    ),
+    ("alias_list", "Aliases: {list}"),
+    ("alias_added", "{prefix}{alias} now runs {prefix}{command}"),
+    ("alias_removed", "Alias {prefix}{alias} removed"),
+    ("alias_missing", "There is no alias {prefix}{alias}"),
+    ("alias_taken", "{prefix}{alias} is already a command"),
+    ("alias_error", "Couldn't save the aliases, check the logs"),
+    ("usage", "@{user}: Usage: {usage}"),
+    (
+        "whisper_hint",
+        "Start your whisper with the channel it's about, e.g. #channel {prefix}position",
+    ),
+    // Announcements
+    ("greeting", "Hello there gamers! {user} is now in chat."),
+    (
+        "reconnected",
+        "I'm back after losing the connection, commands sent in the meantime were missed",
+    ),
+];
+
+fn default(key: &str) -> Option<&'static str> {
+    DEFAULTS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, text)| *text)
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Io(e) => write!(fmt, "{e}"),
+            Self::Toml(e) => write!(fmt, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The messages of a channel, falling back to English for keys it doesn't set
+#[derive(Clone, Debug, Default)]
+pub struct Templates {
+    overrides: HashMap<String, String>,
+}
+
+impl Templates {
+    /// Reads messages from a TOML table of keys to texts, unknown keys are
+    /// skipped with a warning so a typo only costs that one message
+    pub fn parse(text: &str) -> Result<Self, TemplateError> {
+        let mut overrides: HashMap<String, String> =
+            toml::from_str(text).map_err(TemplateError::Toml)?;
+        overrides.retain(|key, _| {
+            let known = key.starts_with(HELP_PREFIX) || default(key).is_some();
+            if !known {
+                warn!("Ignoring unknown message {key}");
+            }
+            known
+        });
+        Ok(Self { overrides })
+    }
+
+    /// Reads messages from `path`, a missing file means English only
+    pub fn load(path: &Path) -> Result<Self, TemplateError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(TemplateError::Io(e)),
+        }
+    }
+
+    /// The raw text of `key`, with its placeholders left in
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        self.overrides
+            .get(key)
+            .map(String::as_str)
+            .or_else(|| default(key))
+            .unwrap_or_else(|| {
+                warn!("Missing message {key}");
+                key
+            })
+    }
+
+    /// The text of `key`, for messages without placeholders
+    pub fn text(&self, key: &str) -> String {
+        self.get(key).to_owned()
+    }
+
+    /// The help text of command `name`, `default` unless overridden
+    pub fn help<'a>(&'a self, name: &str, default: &'a str) -> &'a str {
+        self.overrides
+            .get(&format!("{HELP_PREFIX}{name}"))
+            .map_or(default, String::as_str)
+    }
+
+    /// The text of `key` with each `{name}` in `args` replaced by its value.
+    /// Unknown placeholders are kept as they are.
+    pub fn render(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let mut rendered = String::new();
+        let mut rest = self.get(key);
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let value = after.find('}').and_then(|end| {
+                let name = &after[..end];
+                let (_, value) = args.iter().find(|(arg, _)| *arg == name)?;
+                Some((end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    // Writing to a String can't fail
+                    let _ = write!(rendered, "{value}");
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = after;
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
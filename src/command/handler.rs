@@ -0,0 +1,247 @@
+use tracing::{debug, info, warn};
+
+use super::{lookup, AliasAction, Command, Settings};
+use crate::bot::{Bot, Channel, ChatTransport, Priority, Role, SendResult, Templates};
+
+/// Drops the `@user` a reply starts with, as threading already shows who it's for
+fn unaddressed(user: &str, msg: String) -> String {
+    let Some(rest) = msg
+        .strip_prefix('@')
+        .and_then(|rest| rest.strip_prefix(user))
+    else {
+        return msg;
+    };
+    let rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => msg,
+    }
+}
+
+/// Where a command came from, its replies are sent back the same way
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin<'a> {
+    /// A chat message, with its id if replies can be threaded under it
+    Chat(Option<&'a str>),
+    Whisper,
+}
+
+async fn respond<T: ChatTransport>(
+    bot: &Bot<T>,
+    channel: &str,
+    user: &str,
+    origin: Origin<'_>,
+    // Whether the replies are meant only for `user`
+    personal: bool,
+    replies: Vec<String>,
+    priority: Priority,
+) -> SendResult {
+    for msg in replies {
+        match origin {
+            Origin::Whisper => bot.whisper(user, unaddressed(user, msg)).await?,
+            Origin::Chat(Some(id)) if personal => {
+                bot.reply(channel, id, unaddressed(user, msg), priority)
+                    .await?
+            }
+            Origin::Chat(_) => bot.send_msg(channel, msg, priority).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Handles a whisper as a command in the channel it names or the default
+/// one, with the role `user` last had in that channel's chat
+#[tracing::instrument(skip(bot, settings))]
+pub async fn handle_whisper<T: ChatTransport>(
+    bot: &mut Bot<T>,
+    settings: &Settings,
+    user: &str,
+    msg: &str,
+) -> SendResult {
+    let Some((channel, msg)) = bot.whisper_channel(msg) else {
+        // Without a channel there are only the default messages
+        let hint = Templates::default().render("whisper_hint", &[("prefix", &settings.prefix)]);
+        return bot.whisper(user, hint).await;
+    };
+    let role = bot
+        .channel(&channel)
+        .map_or(Role::Everyone, |state| state.role(user));
+    handle_message(bot, settings, &channel, role, user, Origin::Whisper, msg).await
+}
+
+#[tracing::instrument(skip(bot, settings))]
+pub async fn handle_message<T: ChatTransport>(
+    bot: &mut Bot<T>,
+    settings: &Settings,
+    channel: &str,
+    role: Role,
+    user: &str,
+    origin: Origin<'_>,
+    msg: &str,
+) -> SendResult {
+    info!("Handling message");
+    if let (Origin::Chat(_), Some(state)) = (origin, bot.channel_mut(channel)) {
+        state.seen(user, role);
+    }
+    let prefix = settings.prefix(channel);
+    let resolve = |name: &str| bot.aliases().get(channel, name).and_then(lookup);
+    let Some(invocation) = super::parse(prefix, msg, resolve) else {
+        debug!("Not a command, returning");
+        return Ok(());
+    };
+    if !settings.permissions.allows(role, invocation.spec) {
+        info!("User {user} not authorised to use {}", invocation.spec.name);
+        return Ok(());
+    }
+    // Replies to mod commands are the last to be dropped when chat is busy
+    let priority = if settings.permissions.required(invocation.spec) >= Role::Moderator {
+        Priority::High
+    } else {
+        Priority::Low
+    };
+    let command = match invocation.command() {
+        Ok(command) => command,
+        Err(e) => {
+            debug!("Invalid arguments: {e}");
+            let templates = bot.templates(channel);
+            let usage = super::describe(invocation.spec, prefix, templates);
+            let replies = vec![templates.render("usage", &[("user", &user), ("usage", &usage)])];
+            return respond(bot, channel, user, origin, true, replies, priority).await;
+        }
+    };
+    debug!(?command);
+    // Only commands that ran start a cooldown, a typo in the arguments doesn't
+    let spec = invocation.spec;
+    if role < Role::Moderator {
+        let (global, per_user) = (
+            settings.cooldowns.global(spec),
+            settings.cooldowns.user(spec),
+        );
+        if let Some(state) = bot.channel_mut(channel) {
+            if !state.try_use(spec.name, user, global, per_user) {
+                info!("Ignoring {} from {user}, it is cooling down", spec.name);
+                return Ok(());
+            }
+        }
+    }
+    // Answers meant only for the user are threaded under their message
+    let personal = matches!(
+        command,
+        Command::Join(_) | Command::Name(_) | Command::Position
+    );
+    let replies = match command {
+        Command::AddMe => vec![bot.add_me(channel, user, prefix)],
+        Command::RemoveMe => vec![bot.remove_me(channel, user, prefix)],
+        Command::Status => vec![bot.status(channel)],
+        Command::Alias(action) => vec![alias(bot, channel, prefix, action)],
+        command => {
+            let Some(state) = bot.channel_mut(channel) else {
+                warn!("Ignoring command from unknown channel {channel}");
+                return Ok(());
+            };
+            run(state, settings, prefix, role, user, command)
+        }
+    };
+    respond(bot, channel, user, origin, personal, replies, priority).await
+}
+
+/// Lists, adds or removes the command aliases of `channel`
+fn alias<T: ChatTransport>(
+    bot: &mut Bot<T>,
+    channel: &str,
+    prefix: &str,
+    action: AliasAction,
+) -> String {
+    let templates = bot.templates(channel).clone();
+    let aliases = bot.aliases_mut();
+    match action {
+        AliasAction::List => {
+            let list = aliases.list(channel);
+            if list.is_empty() {
+                return templates.render("no_aliases", &[("prefix", &prefix)]);
+            }
+            let list = list
+                .iter()
+                .map(|(alias, command)| format!("{prefix}{alias} -> {prefix}{command}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            templates.render("alias_list", &[("list", &list)])
+        }
+        AliasAction::Add { alias, command } => {
+            if lookup(&alias).is_some() {
+                return templates.render("alias_taken", &[("alias", &alias), ("prefix", &prefix)]);
+            }
+            let Some(spec) = lookup(&command) else {
+                return templates.render(
+                    "unknown_command",
+                    &[("name", &command), ("prefix", &prefix)],
+                );
+            };
+            if let Err(e) = aliases.add(channel, &alias, spec.name) {
+                warn!("Couldn't save aliases: {e}");
+                return templates.text("alias_error");
+            }
+            templates.render(
+                "alias_added",
+                &[
+                    ("alias", &alias),
+                    ("command", &spec.name),
+                    ("prefix", &prefix),
+                ],
+            )
+        }
+        AliasAction::Remove(alias) => {
+            let key = match aliases.remove(channel, &alias) {
+                Err(e) => {
+                    warn!("Couldn't save aliases: {e}");
+                    "alias_error"
+                }
+                Ok(false) => "alias_missing",
+                Ok(true) => "alias_removed",
+            };
+            templates.render(key, &[("alias", &alias), ("prefix", &prefix)])
+        }
+    }
+}
+
+/// Runs a command against the queue state of a channel and returns the replies
+fn run(
+    state: &mut Channel,
+    settings: &Settings,
+    prefix: &str,
+    role: Role,
+    user: &str,
+    command: Command,
+) -> Vec<String> {
+    match command {
+        Command::Help(name) => {
+            super::help(settings, prefix, state.templates(), role, name.as_deref())
+        }
+        Command::Commands => super::commands(settings, prefix, state.templates(), role),
+        Command::Join(name) => vec![state.join(user, name.as_deref())],
+        Command::Name(name) => vec![state.name(user, name.as_deref())],
+        Command::Leave => vec![state.leave(user)],
+        Command::Position => vec![state.position(user)],
+        Command::Length => vec![state.length()],
+        Command::AddMe | Command::RemoveMe | Command::Status | Command::Alias(_) => {
+            unreachable!("Handled by the bot")
+        }
+        // Mod commands
+        Command::Next => vec![state.next()],
+        Command::List(page) => vec![state.list(page)],
+        Command::Clear => vec![state.clear()],
+        Command::Open => vec![state.open()],
+        Command::Close => vec![state.close()],
+        Command::Reset => vec![state.reset()],
+        Command::Undo => vec![state.undo(prefix)],
+        Command::Redo => vec![state.redo(prefix)],
+        Command::Save => vec![state.save()],
+        Command::Backups => vec![state.backups()],
+        Command::Restore(index) => vec![state.restore(index)],
+        Command::Queues => vec![state.queues()],
+        Command::Delete(name) => vec![state.delete(&name, prefix)],
+        Command::Select(name) => vec![state.select(&name)],
+        Command::Create(name) => vec![state.create(&name, prefix)],
+    }
+}
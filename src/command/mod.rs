@@ -0,0 +1,162 @@
+mod handler;
+mod parser;
+mod registry;
+#[cfg(test)]
+mod tests;
+
+pub use handler::{handle_message, handle_whisper, Origin};
+pub use parser::parse;
+pub use registry::{lookup, Args, CommandSpec, Cooldowns, Permissions, COMMANDS};
+
+use std::collections::HashMap;
+
+use crate::bot::{Role, Templates, MAX_MESSAGE_LEN};
+
+pub const DEFAULT_PREFIX: &str = "!";
+
+/// How commands are recognized and who may use them
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Text every command starts with
+    pub prefix: String,
+    /// Prefixes of channels that don't use `prefix`
+    pub prefixes: HashMap<String, String>,
+    pub permissions: Permissions,
+    /// Time before users can repeat a command, mods aren't held up
+    pub cooldowns: Cooldowns,
+}
+
+impl Settings {
+    /// The text commands in `channel` start with
+    pub fn prefix(&self, channel: &str) -> &str {
+        self.prefixes.get(channel).unwrap_or(&self.prefix)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            prefix: DEFAULT_PREFIX.to_owned(),
+            prefixes: HashMap::new(),
+            permissions: Permissions::default(),
+            cooldowns: Cooldowns::default(),
+        }
+    }
+}
+
+/// What `!alias` does
+#[derive(Debug, PartialEq, Eq)]
+pub enum AliasAction {
+    List,
+    Add { alias: String, command: String },
+    Remove(String),
+}
+
+/// A fully parsed command with its arguments
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Help(Option<String>),
+    Commands,
+    Join(Option<String>),
+    Name(Option<String>),
+    Leave,
+    Position,
+    Length,
+    AddMe,
+    RemoveMe,
+    // Mod commands
+    Next,
+    /// Shows a page of the queue, or the first people if `None`
+    List(Option<usize>),
+    Clear,
+    Open,
+    Close,
+    Reset,
+    Undo,
+    Redo,
+    Save,
+    Backups,
+    Restore(usize),
+    Queues,
+    Status,
+    Alias(AliasAction),
+    Delete(String),
+    Select(String),
+    Create(String),
+}
+
+/// Joins `items` with `", "` into as few messages as possible,
+/// each starting with `header` and fitting into a chat message
+fn chunk(header: &str, items: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut current = String::from(header);
+    for item in items {
+        if current.len() > header.len() && current.len() + 2 + item.len() > MAX_MESSAGE_LEN {
+            messages.push(std::mem::replace(&mut current, String::from(header)));
+        }
+        if current.len() > header.len() {
+            current.push_str(", ");
+        }
+        current.push_str(&item);
+    }
+    messages.push(current);
+    messages
+}
+
+/// The usage of a command followed by its help text
+fn describe(spec: &CommandSpec, prefix: &str, templates: &Templates) -> String {
+    format!(
+        "{} - {}",
+        spec.usage(prefix),
+        templates
+            .help(spec.name, spec.help)
+            .replace("{prefix}", prefix)
+    )
+}
+
+/// Lists the usage of every command available to `role`
+pub fn commands(
+    settings: &Settings,
+    prefix: &str,
+    templates: &Templates,
+    role: Role,
+) -> Vec<String> {
+    chunk(
+        templates.get("commands"),
+        COMMANDS
+            .iter()
+            .filter(|spec| settings.permissions.allows(role, spec))
+            .map(|spec| spec.usage(prefix)),
+    )
+}
+
+/// Describes a single command, or lists all of them if `name` is `None`
+pub fn help(
+    settings: &Settings,
+    prefix: &str,
+    templates: &Templates,
+    role: Role,
+    name: Option<&str>,
+) -> Vec<String> {
+    let Some(name) = name else {
+        let mut messages = vec![templates.render("help", &[("prefix", &prefix)])];
+        messages.extend(commands(settings, prefix, templates, role));
+        return messages;
+    };
+    let name = name.strip_prefix(prefix).unwrap_or(name);
+    match lookup(name) {
+        Some(spec) if settings.permissions.allows(role, spec) => {
+            let mut msg = describe(spec, prefix, templates);
+            if !spec.aliases.is_empty() {
+                let aliases: Vec<_> = spec
+                    .aliases
+                    .iter()
+                    .map(|alias| format!("{prefix}{alias}"))
+                    .collect();
+                msg.push_str(&templates.render("aliases", &[("list", &aliases.join(", "))]));
+            }
+            vec![msg]
+        }
+        _ => vec![templates.render("unknown_command", &[("name", &name), ("prefix", &prefix)])],
+    }
+}
@@ -0,0 +1,121 @@
+use super::{lookup, AliasAction, Args, Command, CommandSpec};
+
+/// Invalid arguments, holding the usage of the command
+#[derive(Debug)]
+pub enum ParseError {
+    MissingArgument(String),
+    InvalidArgument(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::MissingArgument(usage) | Self::InvalidArgument(usage) => {
+                write!(fmt, "Usage: {usage}")
+            }
+        }
+    }
+}
+
+/// A message addressing a known command, with its arguments still unparsed
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    pub spec: &'static CommandSpec,
+    pub prefix: &'a str,
+    pub args: Option<&'a str>,
+}
+
+/// Returns `None` if the message isn't a known command starting with `prefix`.
+/// Names that aren't commands are looked up with `alias`.
+pub fn parse<'a>(
+    prefix: &'a str,
+    msg: &'a str,
+    alias: impl Fn(&str) -> Option<&'static CommandSpec>,
+) -> Option<Invocation<'a>> {
+    let msg = msg.strip_prefix(prefix)?.trim_end();
+    let (name, args) = match msg.split_once(' ') {
+        None => (msg, None),
+        Some((name, args)) => (name, Some(args.trim()).filter(|args| !args.is_empty())),
+    };
+    Some(Invocation {
+        spec: lookup(name).or_else(|| alias(name))?,
+        prefix,
+        args,
+    })
+}
+
+impl Invocation<'_> {
+    pub fn command(&self) -> Result<Command, ParseError> {
+        let spec = self.spec;
+        let usage = || format!("{} - {}", spec.usage(self.prefix), spec.help);
+        let text = match (spec.args, self.args) {
+            (Args::None, _) => None,
+            (Args::Optional(_) | Args::OptionalNumber(_), args) => args.map(str::to_owned),
+            (Args::Required(_) | Args::Number(_), None) => {
+                return Err(ParseError::MissingArgument(usage()))
+            }
+            (Args::Required(_) | Args::Number(_), Some(args)) => Some(args.to_owned()),
+        };
+        let number = match (spec.args, text.as_deref().map(str::parse::<usize>)) {
+            (Args::OptionalNumber(_), None) => None,
+            (Args::Number(_) | Args::OptionalNumber(_), Some(Ok(n))) if n > 0 => Some(n),
+            (Args::Number(_) | Args::OptionalNumber(_), _) => {
+                return Err(ParseError::InvalidArgument(usage()))
+            }
+            _ => None,
+        };
+        let required = || text.clone().unwrap_or_default();
+        let alias = || {
+            let words: Vec<_> = text
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect();
+            // Aliases may be given with the prefix, like in chat
+            let name = |word: &str| word.strip_prefix(self.prefix).unwrap_or(word).to_owned();
+            match words[..] {
+                [] => Ok(AliasAction::List),
+                [action, alias, command] if action.eq_ignore_ascii_case("add") => {
+                    Ok(AliasAction::Add {
+                        alias: name(alias),
+                        command: name(command),
+                    })
+                }
+                [action, alias] if action.eq_ignore_ascii_case("remove") => {
+                    Ok(AliasAction::Remove(name(alias)))
+                }
+                _ => Err(ParseError::InvalidArgument(usage())),
+            }
+        };
+
+        Ok(match spec.name {
+            "help" => Command::Help(text),
+            "commands" => Command::Commands,
+            "join" => Command::Join(text),
+            "name" => Command::Name(text),
+            "leave" => Command::Leave,
+            "position" => Command::Position,
+            "length" => Command::Length,
+            "addme" => Command::AddMe,
+            "removeme" => Command::RemoveMe,
+            "next" => Command::Next,
+            "list" => Command::List(number),
+            "clear" => Command::Clear,
+            "open" => Command::Open,
+            "close" => Command::Close,
+            "reset" => Command::Reset,
+            "undo" => Command::Undo,
+            "redo" => Command::Redo,
+            "save" => Command::Save,
+            "backups" => Command::Backups,
+            "restore" => Command::Restore(number.unwrap_or_default()),
+            "queues" => Command::Queues,
+            "status" => Command::Status,
+            "alias" => Command::Alias(alias()?),
+            "delete" => Command::Delete(required()),
+            "select" => Command::Select(required()),
+            "create" => Command::Create(required()),
+            name => unreachable!("Command {name} is registered but can't be parsed"),
+        })
+    }
+}
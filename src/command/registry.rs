@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::bot::Role;
+
+/// What a command accepts after its name
+#[derive(Clone, Copy, Debug)]
+pub enum Args {
+    None,
+    /// Free text which may be omitted
+    Optional(&'static str),
+    /// Free text which must be present
+    Required(&'static str),
+    /// A number starting at 1
+    Number(&'static str),
+    /// A number starting at 1 which may be omitted
+    OptionalNumber(&'static str),
+}
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// Minimum role allowed to use the command, unless overridden by [`Permissions`]
+    pub role: Role,
+    pub args: Args,
+    /// Description shown by help, `{prefix}` is replaced by the channel's prefix
+    pub help: &'static str,
+}
+
+impl CommandSpec {
+    pub fn usage(&self, prefix: &str) -> String {
+        match self.args {
+            Args::None => format!("{prefix}{}", self.name),
+            Args::Optional(arg) | Args::OptionalNumber(arg) => {
+                format!("{prefix}{} [{arg}]", self.name)
+            }
+            Args::Required(arg) | Args::Number(arg) => format!("{prefix}{} <{arg}>", self.name),
+        }
+    }
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::Optional("command"),
+        help: "Explain a command, or list all of them",
+    },
+    CommandSpec {
+        name: "commands",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::None,
+        help: "List the commands you can use",
+    },
+    CommandSpec {
+        name: "join",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::Optional("player name"),
+        help: "Join the queue, optionally setting your player name",
+    },
+    CommandSpec {
+        name: "name",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::Optional("player name"),
+        help: "Show or set your player name",
+    },
+    CommandSpec {
+        name: "leave",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::None,
+        help: "Leave the queue",
+    },
+    CommandSpec {
+        name: "position",
+        aliases: &["pos"],
+        role: Role::Everyone,
+        args: Args::None,
+        help: "Show your position in the queue",
+    },
+    CommandSpec {
+        name: "length",
+        aliases: &["len"],
+        role: Role::Everyone,
+        args: Args::None,
+        help: "Show the number of people in the queue",
+    },
+    CommandSpec {
+        name: "addme",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::None,
+        help: "Add the bot to your channel, use it in the bot's channel",
+    },
+    CommandSpec {
+        name: "removeme",
+        aliases: &[],
+        role: Role::Everyone,
+        args: Args::None,
+        help: "Remove the bot from your channel, use it in the bot's channel",
+    },
+    CommandSpec {
+        name: "next",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Advance the queue and show who's next",
+    },
+    CommandSpec {
+        name: "list",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::OptionalNumber("page"),
+        help: "List the first people in the queue, or a page of it",
+    },
+    CommandSpec {
+        name: "clear",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Clear the queue",
+    },
+    CommandSpec {
+        name: "open",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Open the queue for signups",
+    },
+    CommandSpec {
+        name: "close",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Close the queue",
+    },
+    CommandSpec {
+        name: "reset",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Reset the player history",
+    },
+    CommandSpec {
+        name: "undo",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Revert the last {prefix}next, {prefix}clear or {prefix}reset",
+    },
+    CommandSpec {
+        name: "redo",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Repeat the last undone action",
+    },
+    CommandSpec {
+        name: "save",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Save the queue to disk",
+    },
+    CommandSpec {
+        name: "backups",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "List the backups of the queue",
+    },
+    CommandSpec {
+        name: "restore",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::Number("backup"),
+        help: "Restore a backup from {prefix}backups",
+    },
+    CommandSpec {
+        name: "queues",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "List the saved queues",
+    },
+    CommandSpec {
+        name: "status",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::None,
+        help: "Show the connection and queue status",
+    },
+    CommandSpec {
+        name: "alias",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::Optional("add <alias> <command> | remove <alias>"),
+        help: "List the aliases of this channel, or add or remove one",
+    },
+    CommandSpec {
+        name: "delete",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::Required("queue name"),
+        help: "Delete a saved queue",
+    },
+    CommandSpec {
+        name: "select",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::Required("queue name"),
+        help: "Select a saved queue",
+    },
+    CommandSpec {
+        name: "create",
+        aliases: &[],
+        role: Role::Moderator,
+        args: Args::Required("queue name"),
+        help: "Create and select a new queue",
+    },
+];
+
+/// Finds a command by its name or one of its aliases, ignoring case
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| {
+        spec.name.eq_ignore_ascii_case(name)
+            || spec
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Minimum roles for commands, overriding the defaults of the registry
+#[derive(Clone, Debug, Default)]
+pub struct Permissions {
+    overrides: HashMap<&'static str, Role>,
+}
+
+impl Permissions {
+    /// Parses a comma separated list of `command:role` pairs, e.g. `list:vip,reset:broadcaster`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut permissions = Self::default();
+        for pair in s.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (name, role) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Expected command:role, got {pair}"))?;
+            permissions.set(name, role)?;
+        }
+        Ok(permissions)
+    }
+
+    /// Requires at least `role` for the command called `name`
+    pub fn set(&mut self, name: &str, role: &str) -> Result<(), String> {
+        let spec = lookup(name.trim()).ok_or_else(|| format!("Unknown command {name}"))?;
+        self.overrides.insert(spec.name, role.parse()?);
+        Ok(())
+    }
+
+    pub fn required(&self, spec: &CommandSpec) -> Role {
+        self.overrides.get(spec.name).copied().unwrap_or(spec.role)
+    }
+
+    pub fn allows(&self, role: Role, spec: &CommandSpec) -> bool {
+        role >= self.required(spec)
+    }
+}
+
+/// Minimum time between uses of commands, in a channel and per user
+#[derive(Clone, Debug, Default)]
+pub struct Cooldowns {
+    global: HashMap<&'static str, Duration>,
+    user: HashMap<&'static str, Duration>,
+}
+
+impl Cooldowns {
+    /// Lets anyone use the command called `name` only `cooldown` after its last use
+    pub fn set_global(&mut self, name: &str, cooldown: Duration) -> Result<(), String> {
+        let spec = lookup(name.trim()).ok_or_else(|| format!("Unknown command {name}"))?;
+        self.global.insert(spec.name, cooldown);
+        Ok(())
+    }
+
+    /// Lets a user use the command called `name` only `cooldown` after they last did
+    pub fn set_user(&mut self, name: &str, cooldown: Duration) -> Result<(), String> {
+        let spec = lookup(name.trim()).ok_or_else(|| format!("Unknown command {name}"))?;
+        self.user.insert(spec.name, cooldown);
+        Ok(())
+    }
+
+    pub fn global(&self, spec: &CommandSpec) -> Duration {
+        self.global.get(spec.name).copied().unwrap_or_default()
+    }
+
+    pub fn user(&self, spec: &CommandSpec) -> Duration {
+        self.user.get(spec.name).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,1133 @@
+use std::time::Duration;
+
+use super::{handle_message, handle_whisper, Cooldowns, Origin, Permissions, Settings};
+use crate::bot::{
+    mock::MockTransport, Aliases, Bot, ChannelList, ChatTransport, Connection, JsonFileStore,
+    MemoryStore, Message, Priority, QueueStore, Role, StoreError,
+};
+use crate::testing::temp_path;
+
+const CHANNEL: &str = "test";
+
+struct Harness {
+    bot: Bot<MockTransport>,
+    settings: Settings,
+}
+
+impl Harness {
+    fn new() -> Self {
+        Self::with_settings(Settings::default())
+    }
+
+    fn with_settings(settings: Settings) -> Self {
+        let mut bot = Bot::new(MockTransport::new());
+        bot.add_channel(CHANNEL, Box::new(MemoryStore::new()));
+        Self { bot, settings }
+    }
+
+    /// Sends `text` as `user` in `channel` and returns the replies with the
+    /// channels they were sent to
+    async fn say_in(
+        &mut self,
+        channel: &str,
+        role: Role,
+        user: &str,
+        text: &str,
+    ) -> Vec<(String, String)> {
+        self.bot.chat.inject(channel, role, user, text);
+        self.handle_next().await
+    }
+
+    /// Sends `text` as `user` in a message with id `id` and returns the
+    /// replies with the ids of the messages they were threaded under
+    async fn say_with_id(
+        &mut self,
+        user: &str,
+        text: &str,
+        id: &str,
+    ) -> (Vec<String>, Vec<String>) {
+        self.bot
+            .chat
+            .inject_with_id(CHANNEL, Role::Everyone, user, text, id);
+        let replies = self.handle_next().await;
+        let replies = replies.into_iter().map(|(_, msg)| msg).collect();
+        (replies, self.bot.chat.take_replied_to())
+    }
+
+    /// Whispers `text` as `user` and returns the whispers sent back
+    async fn whisper(&mut self, user: &str, text: &str) -> Vec<String> {
+        self.bot.chat.inject_whisper(user, text);
+        assert!(self.handle_next().await.is_empty(), "Replied in chat");
+        self.bot
+            .chat
+            .take_whispered()
+            .into_iter()
+            .map(|(to, msg)| {
+                assert_eq!(to, user, "Whispered to the wrong user");
+                msg
+            })
+            .collect()
+    }
+
+    async fn handle_next(&mut self) -> Vec<(String, String)> {
+        match self.bot.recv_msg().await {
+            Some(Message::UserText {
+                channel,
+                role,
+                user,
+                text,
+                id,
+            }) => handle_message(
+                &mut self.bot,
+                &self.settings,
+                &channel,
+                role,
+                &user,
+                Origin::Chat(id.as_deref()),
+                &text,
+            )
+            .await
+            .unwrap(),
+            Some(Message::Whisper { user, text }) => {
+                handle_whisper(&mut self.bot, &self.settings, &user, &text)
+                    .await
+                    .unwrap()
+            }
+            _ => panic!("Injected message wasn't received"),
+        }
+        self.bot.flush_journal();
+        self.bot.chat.take_sent()
+    }
+
+    /// Sends `text` as `user` in the default channel and returns the replies
+    async fn say(&mut self, role: Role, user: &str, text: &str) -> Vec<String> {
+        self.say_in(CHANNEL, role, user, text)
+            .await
+            .into_iter()
+            .map(|(channel, msg)| {
+                assert_eq!(channel, CHANNEL, "Reply sent to the wrong channel");
+                msg
+            })
+            .collect()
+    }
+
+    async fn user(&mut self, user: &str, text: &str) -> Vec<String> {
+        self.say(Role::Everyone, user, text).await
+    }
+
+    async fn moderator(&mut self, text: &str) -> Vec<String> {
+        self.say(Role::Moderator, "mod", text).await
+    }
+
+    /// Creates and opens a queue
+    async fn open_queue(&mut self) {
+        self.moderator("!create Test").await;
+        self.moderator("!open").await;
+    }
+}
+
+#[tokio::test]
+async fn ignores_other_messages() {
+    let mut h = Harness::new();
+    assert!(h.user("alice", "hello there").await.is_empty());
+    assert!(h.user("alice", "!unknown").await.is_empty());
+    assert!(h.user("alice", "join").await.is_empty());
+}
+
+#[tokio::test]
+async fn requires_a_selected_queue() {
+    let mut h = Harness::new();
+    assert_eq!(h.user("alice", "!join").await, ["No Queue selected"]);
+    assert_eq!(h.moderator("!next").await, ["No Queue selected"]);
+}
+
+#[tokio::test]
+async fn create_select_and_delete() {
+    let mut h = Harness::new();
+    assert_eq!(
+        h.moderator("!create Mario Kart").await,
+        ["Queue \"Mario Kart\" has been created and selected"]
+    );
+    h.moderator("!create Other").await;
+    assert_eq!(
+        h.moderator("!queues").await,
+        ["Saved queues: mario-kart, other"]
+    );
+    assert_eq!(
+        h.moderator("!select mario kart").await,
+        ["Queue \"Mario Kart\" is now selected"]
+    );
+    assert_eq!(
+        h.moderator("!select missing").await,
+        ["A queue named missing doesn't exist"]
+    );
+    assert_eq!(
+        h.moderator("!delete mario kart").await,
+        ["The selected queue can't be deleted"]
+    );
+    assert_eq!(
+        h.moderator("!delete other").await,
+        ["Queue other deleted, !create it again and use !restore 1 to get it back"]
+    );
+    assert_eq!(h.moderator("!queues").await, ["Saved queues: mario-kart"]);
+    assert_eq!(
+        h.moderator("!delete other").await,
+        ["A queue named other doesn't exist"]
+    );
+}
+
+#[tokio::test]
+async fn join_leave_and_position() {
+    let mut h = Harness::new();
+    h.moderator("!create Test").await;
+    assert_eq!(
+        h.user("alice", "!join").await,
+        ["Queue is currently closed"]
+    );
+    assert_eq!(h.moderator("!open").await, ["Queue is now open"]);
+    assert_eq!(h.moderator("!open").await, ["Queue is already open"]);
+
+    assert_eq!(
+        h.user("alice", "!join").await,
+        ["@alice: You've been added to the queue at position 1"]
+    );
+    assert_eq!(
+        h.user("bob", "!JOIN").await,
+        ["@bob: You've been added to the queue at position 2"]
+    );
+    assert_eq!(
+        h.user("bob", "!join").await,
+        ["@bob: You're already in queue at position 2"]
+    );
+    assert_eq!(
+        h.user("bob", "!position").await,
+        ["@bob you are number 2 in queue"]
+    );
+    assert_eq!(
+        h.user("bob", "!pos").await,
+        ["@bob you are number 2 in queue"]
+    );
+    assert_eq!(
+        h.user("bob", "!length").await,
+        ["There are 2 people in queue"]
+    );
+
+    assert_eq!(
+        h.user("alice", "!leave").await,
+        ["@alice: You've been removed from the queue"]
+    );
+    assert_eq!(
+        h.user("alice", "!leave").await,
+        ["@alice: You were not queued"]
+    );
+    assert_eq!(
+        h.user("alice", "!position").await,
+        ["@alice: You're not currently queued"]
+    );
+    assert_eq!(h.user("bob", "!len").await, ["There are 1 people in queue"]);
+
+    assert_eq!(h.moderator("!close").await, ["Queue has been closed"]);
+    assert_eq!(h.moderator("!close").await, ["Queue is already closed"]);
+}
+
+#[tokio::test]
+async fn player_names() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    assert_eq!(
+        h.user("alice", "!name").await,
+        ["@alice: Player name not set"]
+    );
+    assert_eq!(
+        h.user("alice", "!name Alice123").await,
+        ["@alice: Player name changed to 'Alice123'"]
+    );
+    assert_eq!(
+        h.user("alice", "!name").await,
+        ["@alice: Current player name is 'Alice123'"]
+    );
+    h.user("bob", "!join Bobby").await;
+    assert_eq!(
+        h.user("bob", "!name").await,
+        ["@bob: Current player name is 'Bobby'"]
+    );
+    h.user("alice", "!join").await;
+    assert_eq!(
+        h.moderator("!next").await,
+        ["@bob (Bobby) is next! @alice is up after that."]
+    );
+}
+
+#[tokio::test]
+async fn next_list_and_reset() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    assert_eq!(h.moderator("!list").await, ["The queue is currently empty"]);
+    assert_eq!(h.moderator("!next").await, ["The queue is currently empty"]);
+    for user in ["a", "b", "c", "d", "e", "f"] {
+        h.user(user, "!join").await;
+    }
+    assert_eq!(
+        h.moderator("!list").await,
+        ["People in queue (first 5 out of 6): [1. a], [2. b], [3. c], [4. d], [5. e]"]
+    );
+    assert_eq!(
+        h.moderator("!next").await,
+        ["@a is next! @b is up after that."]
+    );
+    assert_eq!(
+        h.user("a", "!join").await,
+        ["@a: You've already played. Wait until queue reset to join again."]
+    );
+    assert_eq!(
+        h.moderator("!reset").await,
+        ["Player history has been reset!"]
+    );
+    assert_eq!(
+        h.user("a", "!join").await,
+        ["@a: You've been added to the queue at position 6"]
+    );
+    assert_eq!(
+        h.moderator("!list").await,
+        ["People in queue (first 5 out of 6): [1. b], [2. c], [3. d], [4. e], [5. f]"]
+    );
+    for _ in 0..5 {
+        h.moderator("!next").await;
+    }
+    assert_eq!(
+        h.moderator("!next").await,
+        ["@a is next! That's the last one."]
+    );
+    h.user("b", "!join").await;
+    assert_eq!(
+        h.user("b", "!join").await,
+        ["@b: You've already played. Wait until queue reset to join again."]
+    );
+}
+
+#[tokio::test]
+async fn clear_undo_and_redo() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    assert_eq!(h.moderator("!undo").await, ["There is nothing to undo"]);
+    for user in ["a", "b", "c"] {
+        h.user(user, "!join").await;
+    }
+    h.moderator("!next").await;
+    h.moderator("!next").await;
+    assert_eq!(h.moderator("!undo").await, ["Undid !next (@b)"]);
+    assert_eq!(
+        h.moderator("!list").await,
+        ["People in queue: [1. b], [2. c]"]
+    );
+    assert_eq!(h.moderator("!redo").await, ["Redid !next (@b)"]);
+    assert_eq!(h.moderator("!redo").await, ["There is nothing to redo"]);
+
+    assert_eq!(h.moderator("!clear").await, ["Queue has been cleared"]);
+    h.user("d", "!join").await;
+    assert_eq!(h.moderator("!undo").await, ["Undid !clear"]);
+    assert_eq!(
+        h.moderator("!list").await,
+        ["People in queue: [1. c], [2. d]"]
+    );
+
+    h.moderator("!reset").await;
+    h.moderator("!undo").await;
+    assert_eq!(
+        h.user("a", "!join").await,
+        ["@a: You've already played. Wait until queue reset to join again."]
+    );
+}
+
+#[tokio::test]
+async fn backups_and_restore() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    assert_eq!(
+        h.moderator("!backups").await,
+        ["There are no backups of queue Test"]
+    );
+    h.user("alice", "!join").await;
+    h.moderator("!clear").await;
+    let backups = h.moderator("!backups").await;
+    assert!(backups[0].starts_with("Backups of queue Test: [1. "));
+    assert_eq!(
+        h.moderator("!restore 1").await,
+        ["Queue Test restored from backup 1, there are 1 people in queue"]
+    );
+    assert_eq!(
+        h.moderator("!restore 9").await,
+        ["Backup 9 of queue Test doesn't exist"]
+    );
+    assert_eq!(
+        h.moderator("!create test").await,
+        ["Queue \"test\" has been recreated and selected, use !restore 1 to get the previous one back"]
+    );
+    assert_eq!(
+        h.moderator("!length").await,
+        ["There are 0 people in queue"]
+    );
+    h.moderator("!restore 1").await;
+    assert_eq!(
+        h.moderator("!length").await,
+        ["There are 1 people in queue"]
+    );
+
+    // Deleted queues can be restored as well
+    h.moderator("!create Other").await;
+    h.moderator("!delete test").await;
+    h.moderator("!create test").await;
+    h.moderator("!restore 1").await;
+    assert_eq!(
+        h.moderator("!length").await,
+        ["There are 1 people in queue"]
+    );
+}
+
+#[tokio::test]
+async fn save() {
+    let mut h = Harness::new();
+    assert_eq!(h.moderator("!save").await, ["No Queue selected"]);
+    h.open_queue().await;
+    assert_eq!(h.moderator("!save").await, ["Queue Test saved"]);
+}
+
+#[tokio::test]
+async fn argument_validation() {
+    let mut h = Harness::new();
+    assert_eq!(
+        h.moderator("!select").await,
+        ["@mod: Usage: !select <queue name> - Select a saved queue"]
+    );
+    assert_eq!(
+        h.moderator("!create   ").await,
+        ["@mod: Usage: !create <queue name> - Create and select a new queue"]
+    );
+    assert_eq!(
+        h.moderator("!restore first").await,
+        ["@mod: Usage: !restore <backup> - Restore a backup from !backups"]
+    );
+    assert_eq!(
+        h.moderator("!delete").await,
+        ["@mod: Usage: !delete <queue name> - Delete a saved queue"]
+    );
+}
+
+#[tokio::test]
+async fn mod_commands_require_moderator() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    h.user("alice", "!join").await;
+    for command in [
+        "!next",
+        "!list",
+        "!clear",
+        "!open",
+        "!close",
+        "!reset",
+        "!undo",
+        "!redo",
+        "!save",
+        "!backups",
+        "!restore 1",
+        "!queues",
+        "!delete x",
+        "!select x",
+        "!create x",
+    ] {
+        assert!(
+            h.say(Role::Vip, "vip", command).await.is_empty(),
+            "{command} was allowed"
+        );
+    }
+    assert!(h.user("alice", "!select").await.is_empty());
+    assert_eq!(
+        h.say(Role::Broadcaster, "streamer", "!length").await,
+        ["There are 1 people in queue"]
+    );
+}
+
+#[tokio::test]
+async fn configured_permissions() {
+    let permissions = Permissions::parse("list:vip, reset:broadcaster").unwrap();
+    let mut h = Harness::with_settings(Settings {
+        permissions,
+        ..Settings::default()
+    });
+    h.open_queue().await;
+    assert_eq!(
+        h.say(Role::Vip, "vip", "!list").await,
+        ["The queue is currently empty"]
+    );
+    assert!(h.say(Role::Subscriber, "sub", "!list").await.is_empty());
+    assert!(h.moderator("!reset").await.is_empty());
+    assert_eq!(
+        h.say(Role::Broadcaster, "streamer", "!reset").await,
+        ["Player history has been reset!"]
+    );
+    assert!(Permissions::parse("list:nobody").is_err());
+    assert!(Permissions::parse("missing:vip").is_err());
+}
+
+#[tokio::test]
+async fn help_and_commands() {
+    let mut h = Harness::new();
+    let commands = h.user("alice", "!commands").await;
+    assert_eq!(commands.len(), 1);
+    assert!(commands[0].starts_with("Commands: !help [command], !commands, !join [player name]"));
+    assert!(!commands[0].contains("!next"));
+    let commands = h.moderator("!commands").await;
+    assert!(commands[0].contains("!next"));
+    assert!(commands.iter().all(|msg| msg.len() <= 500));
+
+    assert_eq!(
+        h.user("alice", "!help position").await,
+        ["!position - Show your position in the queue (also !pos)"]
+    );
+    assert_eq!(
+        h.user("alice", "!help !join").await,
+        ["!join [player name] - Join the queue, optionally setting your player name"]
+    );
+    assert_eq!(
+        h.user("alice", "!help next").await,
+        ["Unknown command next, see !commands"]
+    );
+    assert_eq!(
+        h.moderator("!help next").await,
+        ["!next - Advance the queue and show who's next"]
+    );
+    let help = h.user("alice", "!help").await;
+    assert_eq!(help[0], "Use !help <command> to learn more about a command");
+    assert_eq!(help[1..], commands_for(&mut h, Role::Everyone).await[..]);
+}
+
+async fn commands_for(h: &mut Harness, role: Role) -> Vec<String> {
+    h.say(role, "someone", "!commands").await
+}
+
+#[tokio::test]
+async fn transport_records_replies() {
+    let mut chat = MockTransport::new();
+    chat.send_msg("test", "hello".into(), Priority::Low)
+        .await
+        .unwrap();
+    chat.inject("test", Role::Everyone, "alice", "!join");
+    assert_eq!(chat.take_sent(), [("test".to_owned(), "hello".to_owned())]);
+    assert!(chat.take_sent().is_empty());
+    assert!(chat.recv_msg().await.is_some());
+    assert!(chat.recv_msg().await.is_none());
+}
+
+#[tokio::test]
+async fn channels_are_separate() {
+    let mut h = Harness::new();
+    h.bot.add_channel("Other", Box::new(MemoryStore::new()));
+    h.open_queue().await;
+    h.user("alice", "!join").await;
+
+    assert_eq!(
+        h.say_in("other", Role::Everyone, "alice", "!join").await,
+        [("other".to_owned(), "No Queue selected".to_owned())]
+    );
+    h.say_in("other", Role::Moderator, "mod", "!create Test")
+        .await;
+    h.say_in("other", Role::Moderator, "mod", "!open").await;
+    h.say_in("other", Role::Everyone, "bob", "!join").await;
+    h.say_in("other", Role::Everyone, "carol", "!join").await;
+
+    assert_eq!(
+        h.user("bob", "!length").await,
+        ["There are 1 people in queue"]
+    );
+    assert_eq!(
+        h.say_in("other", Role::Everyone, "bob", "!length").await,
+        [("other".to_owned(), "There are 2 people in queue".to_owned())]
+    );
+    assert!(h
+        .say_in("unknown", Role::Moderator, "mod", "!next")
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn add_and_remove_channels() {
+    let mut h = Harness::new();
+    let mut added = ChannelList::in_memory();
+    added.add("earlier").unwrap();
+    h.bot
+        .enable_channel_admin(
+            "QueueBot",
+            added,
+            Box::new(|_| Ok(Box::new(MemoryStore::new()))),
+        )
+        .unwrap();
+    assert_eq!(h.bot.chat.joined(), ["queuebot", "earlier"]);
+
+    assert_eq!(
+        h.user("alice", "!addme").await,
+        ["@alice: Use !addme in #queuebot to add me to your channel"]
+    );
+    assert_eq!(
+        h.say_in("queuebot", Role::Everyone, "alice", "!addme")
+            .await,
+        [(
+            "queuebot".to_owned(),
+            "@alice: I've joined your channel, use !removeme here to make me leave".to_owned()
+        )]
+    );
+    assert_eq!(h.bot.chat.joined(), ["queuebot", "earlier", "alice"]);
+    assert_eq!(
+        h.say_in("queuebot", Role::Everyone, "alice", "!addme")
+            .await[0]
+            .1,
+        "@alice: I'm already in your channel"
+    );
+    assert_eq!(
+        h.say_in("alice", Role::Broadcaster, "alice", "!create Test")
+            .await[0],
+        (
+            "alice".to_owned(),
+            "Queue \"Test\" has been created and selected".to_owned()
+        )
+    );
+
+    assert_eq!(
+        h.say_in("queuebot", Role::Everyone, "test", "!removeme")
+            .await[0]
+            .1,
+        "@test: Your channel is set in the config, ask the bot owner to remove it"
+    );
+    assert_eq!(
+        h.say_in("queuebot", Role::Everyone, "alice", "!removeme")
+            .await[0]
+            .1,
+        "@alice: I've left your channel"
+    );
+    assert_eq!(
+        h.say_in("queuebot", Role::Everyone, "alice", "!removeme")
+            .await[0]
+            .1,
+        "@alice: I'm not in your channel"
+    );
+    assert_eq!(h.bot.chat.joined(), ["queuebot", "earlier"]);
+    assert!(h
+        .say_in("alice", Role::Broadcaster, "alice", "!queues")
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn unopenable_store_is_not_joined() {
+    let mut h = Harness::new();
+    let mut added = ChannelList::in_memory();
+    added.add("broken").unwrap();
+    h.bot
+        .enable_channel_admin(
+            "QueueBot",
+            added,
+            Box::new(|channel| match channel {
+                "queuebot" | "alice" => Ok(Box::new(MemoryStore::new())),
+                _ => Err(StoreError::Io(std::io::ErrorKind::PermissionDenied.into())),
+            }),
+        )
+        .unwrap();
+    assert_eq!(h.bot.chat.joined(), ["queuebot"]);
+
+    assert_eq!(
+        h.say_in("queuebot", Role::Everyone, "bob", "!addme").await[0].1,
+        "@bob: Couldn't join your channel"
+    );
+    assert_eq!(h.bot.chat.joined(), ["queuebot"]);
+    h.say_in("queuebot", Role::Everyone, "alice", "!addme")
+        .await;
+    assert_eq!(h.bot.chat.joined(), ["queuebot", "alice"]);
+}
+
+#[tokio::test]
+async fn custom_prefix_and_list_size() {
+    let mut h = Harness::with_settings(Settings {
+        prefix: "?q ".into(),
+        ..Settings::default()
+    });
+    h.bot.set_list_size(2);
+    assert!(h.moderator("!create Test").await.is_empty());
+    h.moderator("?q create Test").await;
+    h.moderator("?q open").await;
+    for user in ["a", "b", "c"] {
+        h.user(user, "?q join").await;
+    }
+    assert_eq!(
+        h.moderator("?q list").await,
+        ["People in queue (first 2 out of 3): [1. a], [2. b]"]
+    );
+    assert_eq!(
+        h.moderator("?q restore").await,
+        ["@mod: Usage: ?q restore <backup> - Restore a backup from ?q backups"]
+    );
+    assert_eq!(
+        h.user("a", "?q help ?q pos").await,
+        ["?q position - Show your position in the queue (also ?q pos)"]
+    );
+    h.moderator("?q next").await;
+    assert_eq!(h.moderator("?q undo").await, ["Undid ?q next (@a)"]);
+    assert_eq!(
+        h.moderator("?q create Test").await,
+        ["Queue \"Test\" has been recreated and selected, use ?q restore 1 to get the previous one back"]
+    );
+}
+
+#[tokio::test]
+async fn queue_names_stay_inside_the_channel() {
+    let dir = temp_path("traversal");
+    let store = |channel| {
+        let dir = crate::bot::store::channel_dir(&dir, channel);
+        Box::new(JsonFileStore::new(dir).unwrap())
+    };
+    let mut h = Harness::new();
+    h.bot.add_channel("attacker", store("attacker"));
+    h.bot.add_channel("victim", store("victim"));
+    h.say_in("victim", Role::Broadcaster, "victim", "!create Secret")
+        .await;
+    h.say_in("attacker", Role::Broadcaster, "attacker", "!create Own")
+        .await;
+
+    for command in [
+        "!select ../victim/secret",
+        "!delete ../victim/secret",
+        "!create ../victim/secret",
+        "!delete ../token",
+        "!select ..",
+    ] {
+        assert_eq!(
+            h.say_in("attacker", Role::Broadcaster, "attacker", command)
+                .await,
+            [(
+                "attacker".to_owned(),
+                "Queue names may only contain letters, digits, spaces, - and _".to_owned()
+            )],
+            "{command}"
+        );
+    }
+    assert_eq!(
+        h.say_in("victim", Role::Broadcaster, "victim", "!queues")
+            .await[0]
+            .1,
+        "Saved queues: secret"
+    );
+    assert!(!dir.join("victim").join("secret.journal").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn autosave_flushes_changed_queues() {
+    let dir = temp_path("autosave");
+    let mut h = Harness::new();
+    h.bot
+        .add_channel("saved", Box::new(JsonFileStore::new(&dir).unwrap()));
+    h.say_in("saved", Role::Moderator, "mod", "!create Test")
+        .await;
+    h.say_in("saved", Role::Moderator, "mod", "!open").await;
+    h.say_in("saved", Role::Everyone, "alice", "!join").await;
+    let journal = dir.join("test.journal");
+    assert!(journal.exists());
+
+    h.bot.chat.idle = true;
+    h.bot.enable_autosave(Duration::from_millis(20));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), h.bot.recv_msg())
+            .await
+            .is_err()
+    );
+    // The snapshot now holds the change, so the journal is gone
+    assert!(!journal.exists());
+    let queue = JsonFileStore::new(&dir)
+        .unwrap()
+        .load("Test")
+        .unwrap()
+        .unwrap();
+    assert_eq!(queue.list(), ["alice"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn status() {
+    let mut h = Harness::new();
+    assert!(h.user("alice", "!status").await.is_empty());
+    assert_eq!(
+        h.moderator("!status").await,
+        ["Status: connected for 0s, no queue selected"]
+    );
+
+    h.open_queue().await;
+    h.user("alice", "!join").await;
+    h.bot.chat.health.joining(CHANNEL);
+    h.bot.chat.health.transition(Connection::Reconnecting);
+    h.bot.chat.health.transition(Connection::Connected);
+    h.bot.chat.health.joined(CHANNEL);
+    assert_eq!(
+        h.moderator("!status").await,
+        ["Status: connected for 0s, 1 reconnect, channel joined, queue Test is open with 1 people"]
+    );
+
+    h.bot.chat.health.failed(CHANNEL, "msg_banned");
+    h.bot.chat.health.transition(Connection::Reconnecting);
+    assert_eq!(
+        h.moderator("!status").await,
+        ["Status: reconnecting for 0s, 1 reconnect, channel failed (msg_banned), queue Test is open with 1 people"]
+    );
+}
+
+#[tokio::test]
+async fn list_pages() {
+    let mut h = Harness::new();
+    h.bot.set_list_size(2);
+    h.open_queue().await;
+    for user in ["a", "b", "c", "d", "e"] {
+        h.user(user, "!join").await;
+    }
+    assert_eq!(
+        h.moderator("!list 1").await,
+        ["People in queue (page 1 of 3, 5 in total): [1. a], [2. b]"]
+    );
+    assert_eq!(
+        h.moderator("!list 3").await,
+        ["People in queue (page 3 of 3, 5 in total): [5. e]"]
+    );
+    assert_eq!(h.moderator("!list 4").await, ["There are only 3 pages"]);
+    assert_eq!(
+        h.moderator("!list first").await,
+        ["@mod: Usage: !list [page] - List the first people in the queue, or a page of it"]
+    );
+}
+
+#[tokio::test]
+async fn long_replies_are_split() {
+    let mut h = Harness::new();
+    h.bot.set_list_size(40);
+    h.open_queue().await;
+    for n in 0..40 {
+        h.user(&format!("a_rather_long_username{n:02}"), "!join")
+            .await;
+    }
+    let replies = h.moderator("!list").await;
+    assert_eq!(replies.len(), 3);
+    assert!(replies[0].starts_with("People in queue: [1. a_rather_long_username00], [2."));
+    for reply in &replies {
+        assert!(reply.len() <= 500, "Reply too long: {reply}");
+        assert!(reply.ends_with(']'));
+    }
+    assert!(replies[1].starts_with('['));
+    assert!(replies[2].ends_with("[40. a_rather_long_username39]"));
+
+    // Without entries, messages are split between words
+    let name = "word ".repeat(120);
+    h.user("a_rather_long_username00", &format!("!name {name}"))
+        .await;
+    let replies = h.moderator("!next").await;
+    assert_eq!(replies.len(), 2);
+    assert!(replies[0].ends_with("word"));
+    assert!(replies[1].starts_with("word"));
+}
+
+#[tokio::test]
+async fn threaded_replies() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    assert_eq!(
+        h.say_with_id("alice", "!join Alice123", "m1").await,
+        (
+            vec!["You've been added to the queue at position 1".to_owned()],
+            vec!["m1".to_owned()]
+        )
+    );
+    assert_eq!(
+        h.say_with_id("alice", "!position", "m2").await,
+        (
+            vec!["You are number 1 in queue".to_owned()],
+            vec!["m2".to_owned()]
+        )
+    );
+    assert_eq!(
+        h.say_with_id("alice", "!name", "m3").await,
+        (
+            vec!["Current player name is 'Alice123'".to_owned()],
+            vec!["m3".to_owned()]
+        )
+    );
+    // Other replies are still sent to the whole chat
+    assert_eq!(
+        h.say_with_id("alice", "!length", "m4").await,
+        (vec!["There are 1 people in queue".to_owned()], vec![])
+    );
+    assert_eq!(
+        h.say_with_id("alice", "!leave", "m5").await.1,
+        Vec::<String>::new()
+    );
+}
+
+#[tokio::test]
+async fn whispers() {
+    let mut h = Harness::new();
+    h.open_queue().await;
+    h.user("alice", "!join").await;
+    assert_eq!(
+        h.whisper("alice", "!position").await,
+        ["You are number 1 in queue"]
+    );
+    assert_eq!(
+        h.whisper("alice", "!name Alice123").await,
+        ["Player name changed to 'Alice123'"]
+    );
+    assert!(h.whisper("alice", "hello").await.is_empty());
+    assert_eq!(
+        h.whisper("alice", "#nowhere !position").await,
+        ["Start your whisper with the channel it's about, e.g. #channel !position"]
+    );
+
+    // Roles are taken from the chat of the channel
+    assert!(h.whisper("bob", "!next").await.is_empty());
+    h.say(Role::Moderator, "bob", "hi chat").await;
+    assert_eq!(
+        h.whisper("bob", "#test !next").await,
+        ["@alice (Alice123) is next! That's the last one."]
+    );
+    assert_eq!(
+        h.whisper("test", "!length").await,
+        ["There are 0 people in queue"]
+    );
+
+    // Without a default channel whispers have to name one
+    h.bot.add_channel("other", Box::new(MemoryStore::new()));
+    assert_eq!(
+        h.whisper("alice", "!position").await,
+        ["Start your whisper with the channel it's about, e.g. #channel !position"]
+    );
+    h.bot.set_whisper_channel(Some("Other"));
+    assert_eq!(h.whisper("alice", "!position").await, ["No Queue selected"]);
+}
+
+#[tokio::test]
+async fn channel_messages() {
+    let dir = temp_path("messages");
+    let channel_dir = crate::bot::store::channel_dir(&dir, CHANNEL);
+    std::fs::create_dir_all(&channel_dir).unwrap();
+    std::fs::write(
+        channel_dir.join("messages.toml"),
+        r#"
+joined = "@{user}: Estás en la posición {position} de la cola {queue}"
+queue_open = "La cola está abierta"
+help_join = "Únete a la cola"
+status = "Estado: {connection} desde hace {uptime}"
+status_no_queue = ", ninguna cola seleccionada"
+connection_connected = "conectado"
+"#,
+    )
+    .unwrap();
+    let mut h = Harness::new();
+    h.bot.set_template_dir(Some(&dir));
+    assert_eq!(
+        h.moderator("!status").await,
+        ["Estado: conectado desde hace 0s, ninguna cola seleccionada"]
+    );
+    h.moderator("!create Test").await;
+    assert_eq!(h.moderator("!open").await, ["La cola está abierta"]);
+    assert_eq!(
+        h.user("alice", "!join").await,
+        ["@alice: Estás en la posición 1 de la cola Test"]
+    );
+    // Missing keys fall back to English
+    assert_eq!(
+        h.user("alice", "!position").await,
+        ["@alice you are number 1 in queue"]
+    );
+    assert_eq!(
+        h.user("alice", "!help join").await,
+        ["!join [player name] - Únete a la cola"]
+    );
+
+    // Only the unknown keys of a file are skipped
+    std::fs::write(
+        channel_dir.join("messages.toml"),
+        "queue_opened = \"Offen\"\nqueue_close = \"Geschlossen\"",
+    )
+    .unwrap();
+    h.bot.reload_templates();
+    assert_eq!(h.moderator("!close").await, ["Geschlossen"]);
+    assert_eq!(h.moderator("!open").await, ["Queue is now open"]);
+
+    // A file that isn't valid TOML is ignored as a whole
+    std::fs::write(channel_dir.join("messages.toml"), "queue_close = ").unwrap();
+    h.bot.reload_templates();
+    assert_eq!(h.moderator("!close").await, ["Queue has been closed"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn aliases() {
+    let dir = temp_path("aliases");
+    let mut h = Harness::new();
+    h.bot.set_aliases(Aliases::open(&dir).unwrap());
+    h.open_queue().await;
+    assert_eq!(
+        h.moderator("!alias").await,
+        ["There are no aliases, add one with !alias add <alias> <command>"]
+    );
+    assert_eq!(
+        h.moderator("!alias add !q !join").await,
+        ["!q now runs !join"]
+    );
+    assert_eq!(
+        h.moderator("!alias add where pos").await,
+        ["!where now runs !position"]
+    );
+    assert!(h.user("alice", "!alias add x next").await.is_empty());
+    assert_eq!(
+        h.moderator("!alias add pos leave").await,
+        ["!pos is already a command"]
+    );
+    assert_eq!(
+        h.moderator("!alias add x nope").await,
+        ["Unknown command nope, see !commands"]
+    );
+    assert_eq!(
+        h.moderator("!alias add x").await,
+        ["@mod: Usage: !alias [add <alias> <command> | remove <alias>] - List the aliases of this channel, or add or remove one"]
+    );
+
+    assert_eq!(
+        h.user("alice", "!Q Alice").await,
+        ["@alice: You've been added to the queue at position 1"]
+    );
+    assert_eq!(
+        h.user("alice", "!where").await,
+        ["@alice you are number 1 in queue"]
+    );
+    // Aliases belong to the channel they were added in
+    h.bot.add_channel("other", Box::new(MemoryStore::new()));
+    assert!(h
+        .say_in("other", Role::Everyone, "alice", "!q")
+        .await
+        .is_empty());
+
+    let persisted = Aliases::open(&dir).unwrap();
+    assert_eq!(
+        persisted.list(CHANNEL),
+        [("q", "join"), ("where", "position")]
+    );
+    assert_eq!(
+        h.moderator("!alias").await,
+        ["Aliases: !q -> !join, !where -> !position"]
+    );
+    assert_eq!(h.moderator("!alias remove q").await, ["Alias !q removed"]);
+    assert_eq!(
+        h.moderator("!alias remove q").await,
+        ["There is no alias !q"]
+    );
+    assert!(h.user("bob", "!q").await.is_empty());
+    assert_eq!(
+        Aliases::open(&dir).unwrap().list(CHANNEL),
+        [("where", "position")]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn aliases_are_read_again_on_reload() {
+    let dir = temp_path("alias_reload");
+    let mut h = Harness::new();
+    h.bot.set_aliases(Aliases::open(&dir).unwrap());
+    h.open_queue().await;
+    assert!(h.user("alice", "!q").await.is_empty());
+
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("aliases.json"), r#"{"test":{"q":"join"}}"#).unwrap();
+    h.bot.reload_aliases();
+    assert_eq!(
+        h.user("alice", "!q").await,
+        ["@alice: You've been added to the queue at position 1"]
+    );
+    // A broken file keeps the current aliases
+    std::fs::write(dir.join("aliases.json"), "{").unwrap();
+    h.bot.reload_aliases();
+    assert_eq!(h.bot.aliases().list(CHANNEL), [("q", "join")]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn channel_prefixes() {
+    let mut h = Harness::with_settings(Settings {
+        prefixes: [(CHANNEL.to_owned(), "?q ".to_owned())].into(),
+        ..Settings::default()
+    });
+    h.bot.add_channel("other", Box::new(MemoryStore::new()));
+    assert!(h.moderator("!create Test").await.is_empty());
+    assert_eq!(
+        h.moderator("?q create Test").await,
+        ["Queue \"Test\" has been created and selected"]
+    );
+    assert_eq!(
+        h.moderator("?q alias add j join").await,
+        ["?q j now runs ?q join"]
+    );
+    assert_eq!(
+        h.say_in("other", Role::Moderator, "mod", "!queues").await,
+        [("other".to_owned(), "There are no saved queues".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn cooldowns() {
+    let cooldown = Duration::from_millis(200);
+    let mut cooldowns = Cooldowns::default();
+    cooldowns.set_user("position", cooldown).unwrap();
+    cooldowns.set_global("length", cooldown).unwrap();
+    let mut h = Harness::with_settings(Settings {
+        cooldowns,
+        ..Settings::default()
+    });
+    h.open_queue().await;
+    h.user("alice", "!join").await;
+
+    assert_eq!(h.user("alice", "!pos").await.len(), 1);
+    assert!(h.user("alice", "!position").await.is_empty());
+    // The cooldown is per user
+    assert_eq!(h.user("bob", "!position").await.len(), 1);
+    assert_eq!(h.user("alice", "!length").await.len(), 1);
+    // and for everyone
+    assert!(h.user("bob", "!length").await.is_empty());
+    // Mods aren't held up
+    assert_eq!(h.moderator("!length").await.len(), 1);
+    assert_eq!(h.moderator("!length").await.len(), 1);
+    // Other commands aren't affected
+    assert_eq!(h.user("alice", "!name").await.len(), 1);
+
+    tokio::time::sleep(cooldown).await;
+    assert_eq!(
+        h.user("alice", "!position").await,
+        ["@alice you are number 1 in queue"]
+    );
+    assert_eq!(h.user("bob", "!length").await.len(), 1);
+}
+
+#[tokio::test]
+async fn invalid_arguments_start_no_cooldown() {
+    let mut cooldowns = Cooldowns::default();
+    cooldowns.set_user("list", Duration::from_secs(60)).unwrap();
+    let mut h = Harness::with_settings(Settings {
+        permissions: Permissions::parse("list:everyone").unwrap(),
+        cooldowns,
+        ..Settings::default()
+    });
+    h.open_queue().await;
+    h.user("alice", "!join").await;
+
+    assert_eq!(
+        h.user("alice", "!list first").await,
+        ["@alice: Usage: !list [page] - List the first people in the queue, or a page of it"]
+    );
+    assert_eq!(
+        h.user("alice", "!list").await,
+        ["People in queue: [1. alice]"]
+    );
+    assert!(h.user("alice", "!list").await.is_empty());
+}
@@ -0,0 +1,645 @@
+//! Bot configuration, read from a TOML file and overridden by environment
+//! variables, which are in turn overridden by command line flags.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::bot::store::{DATA_DIR, TOKEN_FILE};
+use crate::bot::{FileTokenStorage, Login, DEFAULT_BACKUP_COUNT, DEFAULT_LIST_SIZE};
+use crate::command::{Cooldowns, Permissions, Settings, DEFAULT_PREFIX};
+
+#[cfg(test)]
+mod tests;
+
+pub const DEFAULT_PATH: &str = "config.toml";
+/// `KEY=VALUE` file read by earlier versions, used if there is no TOML file
+const LEGACY_PATH: &str = "config.txt";
+/// Environment variables are named after the keys, e.g. `QUEUE_BOT_LIST_SIZE`
+const ENV_PREFIX: &str = "QUEUE_BOT_";
+const DEFAULT_AUTOSAVE_INTERVAL: u64 = 30;
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Keys that can be set, in the order they are documented
+const KEYS: &[&str] = &[
+    "bot_username",
+    "oauth_token",
+    "client_id",
+    "client_secret",
+    "refresh_token",
+    "token_file",
+    "channels",
+    "data_dir",
+    "messages_dir",
+    "storage",
+    "prefix",
+    "prefixes",
+    "list_size",
+    "autosave_interval",
+    "backup_count",
+    "announce_reconnect",
+    "verified_bot",
+    "whisper_channel",
+    "permissions",
+    "global_cooldowns",
+    "user_cooldowns",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Toml(PathBuf, toml::de::Error),
+    UnknownKey(String),
+    Missing(&'static str),
+    Invalid {
+        key: String,
+        reason: String,
+    },
+    /// Bad command line, holding the usage
+    Usage(String),
+}
+
+impl ConfigError {
+    fn invalid(key: &str, reason: impl Into<String>) -> Self {
+        Self::Invalid {
+            key: key.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Io(path, e) => write!(fmt, "Couldn't read {}: {e}", path.display()),
+            Self::Toml(path, e) => write!(fmt, "Invalid config in {}: {e}", path.display()),
+            Self::UnknownKey(key) => write!(fmt, "Unknown config key {key}"),
+            Self::Missing(key) => write!(fmt, "{key} must be set in the config"),
+            Self::Invalid { key, reason } => write!(fmt, "Invalid {key}: {reason}"),
+            Self::Usage(usage) => write!(fmt, "{usage}"),
+        }
+    }
+}
+
+/// Where queues are persisted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    Json,
+    Memory,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl std::str::FromStr for Storage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "memory" => Ok(Self::Memory),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(Self::Sqlite),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => Err("sqlite requires building with --features sqlite".into()),
+            other => Err(format!("Unknown storage backend {other}")),
+        }
+    }
+}
+
+/// Login with a user access token that is refreshed before it expires
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefreshingLogin {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Seeds the token file if it doesn't exist yet
+    pub refresh_token: Option<String>,
+    pub token_file: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bot_username: String,
+    /// Static token, the fallback if refreshing tokens aren't set up
+    pub oauth_token: Option<String>,
+    pub refreshing: Option<RefreshingLogin>,
+    /// Channels joined on startup besides the bot's own one
+    pub channels: Vec<String>,
+    pub data_dir: PathBuf,
+    /// Where the `<channel>/messages.toml` files are read from, data_dir if not set
+    pub messages_dir: PathBuf,
+    pub storage: Storage,
+    pub prefix: String,
+    /// Prefixes of channels that don't use `prefix`
+    pub prefixes: BTreeMap<String, String>,
+    /// Number of people shown by !list
+    pub list_size: usize,
+    /// How often changed queues are saved, zero disables autosaving
+    pub autosave_interval: Duration,
+    /// Number of backups kept per queue
+    pub backup_count: usize,
+    /// Tell the channels when the bot is back after losing the connection
+    pub announce_reconnect: bool,
+    /// Whether Twitch verified the bot account, which raises its rate limit
+    pub verified_bot: bool,
+    /// Channel whispers are about unless they name one, the first of
+    /// `channels` if not set
+    pub whisper_channel: Option<String>,
+    pub permissions: Permissions,
+    pub cooldowns: Cooldowns,
+    /// File the config was read from
+    pub path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Picks how to log into chat. Refreshing tokens are used once the token
+    /// file exists or can be seeded from refresh_token, otherwise the static
+    /// oauth_token is used as a fallback.
+    pub fn login(&self) -> Result<Login, ConfigError> {
+        let static_login = || {
+            self.oauth_token
+                .clone()
+                .map(|oauth_token| Login::Static { oauth_token })
+        };
+        let Some(refreshing) = &self.refreshing else {
+            return static_login().ok_or(ConfigError::Missing("oauth_token"));
+        };
+        let storage = FileTokenStorage::new(&refreshing.token_file);
+        if !storage.exists() {
+            match &refreshing.refresh_token {
+                Some(refresh_token) => storage
+                    .seed(refresh_token)
+                    .map_err(|e| ConfigError::Io(refreshing.token_file.clone(), e))?,
+                None => {
+                    let path = refreshing.token_file.display();
+                    let Some(login) = static_login() else {
+                        return Err(ConfigError::invalid(
+                            "token_file",
+                            format!("{path} doesn't exist, set refresh_token to create it"),
+                        ));
+                    };
+                    warn!("Token file {path} doesn't exist, falling back to oauth_token");
+                    return Ok(login);
+                }
+            }
+        }
+        Ok(Login::Refreshing {
+            client_id: refreshing.client_id.clone(),
+            client_secret: refreshing.client_secret.clone(),
+            storage,
+        })
+    }
+
+    /// Names the keys changed in `new` that only take effect after a restart
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.bot_username != new.bot_username {
+            keys.push("bot_username");
+        }
+        if self.oauth_token != new.oauth_token {
+            keys.push("oauth_token");
+        }
+        let (old, refreshing) = (self.refreshing.as_ref(), new.refreshing.as_ref());
+        if old.map(|r| &r.client_id) != refreshing.map(|r| &r.client_id) {
+            keys.push("client_id");
+        }
+        if old.map(|r| &r.client_secret) != refreshing.map(|r| &r.client_secret) {
+            keys.push("client_secret");
+        }
+        if old.map(|r| &r.token_file) != refreshing.map(|r| &r.token_file) {
+            keys.push("token_file");
+        }
+        if self.channels != new.channels {
+            keys.push("channels");
+        }
+        if self.data_dir != new.data_dir {
+            keys.push("data_dir");
+        }
+        if self.storage != new.storage {
+            keys.push("storage");
+        }
+        if self.verified_bot != new.verified_bot {
+            keys.push("verified_bot");
+        }
+        keys
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            prefix: self.prefix.clone(),
+            prefixes: self.prefixes.clone().into_iter().collect(),
+            permissions: self.permissions.clone(),
+            cooldowns: self.cooldowns.clone(),
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::invalid(key, format!("{value} is not a number")))
+}
+
+fn boolean(key: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(ConfigError::invalid(
+            key,
+            format!("{value} is not true or false"),
+        )),
+    }
+}
+
+/// The config as written, every key is optional until validated
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    bot_username: Option<String>,
+    oauth_token: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    refresh_token: Option<String>,
+    token_file: Option<PathBuf>,
+    channels: Option<Vec<String>>,
+    data_dir: Option<PathBuf>,
+    messages_dir: Option<PathBuf>,
+    storage: Option<String>,
+    prefix: Option<String>,
+    /// Command prefix per channel name
+    #[serde(default)]
+    prefixes: BTreeMap<String, String>,
+    list_size: Option<usize>,
+    autosave_interval: Option<u64>,
+    backup_count: Option<usize>,
+    announce_reconnect: Option<bool>,
+    verified_bot: Option<bool>,
+    whisper_channel: Option<String>,
+    /// Minimum role per command name
+    #[serde(default)]
+    permissions: BTreeMap<String, String>,
+    /// Seconds between uses of a command by anyone, per command name
+    #[serde(default)]
+    global_cooldowns: BTreeMap<String, u64>,
+    /// Seconds between uses of a command by the same user, per command name
+    #[serde(default)]
+    user_cooldowns: BTreeMap<String, u64>,
+}
+
+impl RawConfig {
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Toml(path.to_owned(), e))
+    }
+
+    /// Reads the `KEY=VALUE` lines of a legacy `config.txt`
+    fn read_legacy(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let mut raw = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                let key = format!("{} line {}", path.display(), n + 1);
+                return Err(ConfigError::invalid(&key, "expected KEY=VALUE"));
+            };
+            match key.trim().to_lowercase().as_str() {
+                "channel_name" => raw.set("channels", value)?,
+                key => raw.set(key, value)?,
+            }
+        }
+        Ok(raw)
+    }
+
+    /// Overrides `key` with a value given as text
+    fn set(&mut self, key: &str, text: &str) -> Result<(), ConfigError> {
+        let value = text.trim();
+        match key {
+            "bot_username" => self.bot_username = Some(value.to_owned()),
+            "oauth_token" => self.oauth_token = Some(value.to_owned()),
+            "client_id" => self.client_id = Some(value.to_owned()),
+            "client_secret" => self.client_secret = Some(value.to_owned()),
+            "refresh_token" => self.refresh_token = Some(value.to_owned()),
+            "token_file" => self.token_file = Some(value.into()),
+            "channels" => {
+                self.channels = Some(
+                    value
+                        .split(',')
+                        .filter(|channel| !channel.trim().is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                );
+            }
+            "data_dir" => self.data_dir = Some(value.into()),
+            "messages_dir" => self.messages_dir = Some(value.into()),
+            "storage" => self.storage = Some(value.to_owned()),
+            // Leading spaces are trimmed, trailing ones are part of the prefix
+            "prefix" => self.prefix = Some(text.trim_start().to_owned()),
+            "prefixes" => {
+                for pair in text.split(',').filter(|pair| !pair.trim().is_empty()) {
+                    let (channel, prefix) = pair.split_once(':').ok_or_else(|| {
+                        ConfigError::invalid(key, format!("expected channel:prefix, got {pair}"))
+                    })?;
+                    self.prefixes
+                        .insert(channel.trim().to_owned(), prefix.trim_start().to_owned());
+                }
+            }
+            "list_size" => self.list_size = Some(number(key, value)?),
+            "autosave_interval" => self.autosave_interval = Some(number(key, value)?),
+            "backup_count" => self.backup_count = Some(number(key, value)?),
+            "announce_reconnect" => self.announce_reconnect = Some(boolean(key, value)?),
+            "verified_bot" => self.verified_bot = Some(boolean(key, value)?),
+            "whisper_channel" => self.whisper_channel = Some(value.to_owned()),
+            "permissions" => {
+                for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                    let (name, role) = pair.split_once(':').ok_or_else(|| {
+                        ConfigError::invalid(key, format!("expected command:role, got {pair}"))
+                    })?;
+                    self.permissions
+                        .insert(name.trim().to_owned(), role.trim().to_owned());
+                }
+            }
+            "global_cooldowns" | "user_cooldowns" => {
+                for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                    let (name, seconds) = pair.split_once(':').ok_or_else(|| {
+                        ConfigError::invalid(key, format!("expected command:seconds, got {pair}"))
+                    })?;
+                    let cooldowns = if key == "global_cooldowns" {
+                        &mut self.global_cooldowns
+                    } else {
+                        &mut self.user_cooldowns
+                    };
+                    cooldowns.insert(name.trim().to_owned(), number(key, seconds.trim())?);
+                }
+            }
+            _ => return Err(ConfigError::UnknownKey(key.to_owned())),
+        }
+        Ok(())
+    }
+
+    fn validate(self) -> Result<Config, ConfigError> {
+        let bot_username = self
+            .bot_username
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .ok_or(ConfigError::Missing("bot_username"))?;
+        let oauth_token = self
+            .oauth_token
+            .map(|token| token.trim().to_owned())
+            .filter(|token| !token.is_empty());
+        let data_dir = self.data_dir.unwrap_or_else(|| DATA_DIR.into());
+        let messages_dir = self.messages_dir.unwrap_or_else(|| data_dir.clone());
+        if messages_dir.as_os_str().is_empty() {
+            return Err(ConfigError::invalid("messages_dir", "must not be empty"));
+        }
+        let refreshing = match (self.client_id, self.client_secret) {
+            (None, None) if oauth_token.is_none() => {
+                return Err(ConfigError::Missing("oauth_token"))
+            }
+            (None, None) => None,
+            (Some(_), None) => return Err(ConfigError::Missing("client_secret")),
+            (None, Some(_)) => return Err(ConfigError::Missing("client_id")),
+            (Some(client_id), Some(client_secret)) => Some(RefreshingLogin {
+                client_id,
+                client_secret,
+                refresh_token: self.refresh_token,
+                token_file: self.token_file.unwrap_or_else(|| data_dir.join(TOKEN_FILE)),
+            }),
+        };
+
+        let mut channels = Vec::new();
+        for channel in self.channels.unwrap_or_default() {
+            let channel = channel.trim().to_lowercase();
+            if channel.is_empty()
+                || !channel
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(ConfigError::invalid(
+                    "channels",
+                    format!("{channel:?} is not a channel name"),
+                ));
+            }
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+
+        let whisper_channel = self
+            .whisper_channel
+            .map(|channel| channel.trim().trim_start_matches('#').to_lowercase())
+            .filter(|channel| !channel.is_empty())
+            .or_else(|| channels.first().cloned());
+
+        let storage = match self.storage {
+            None => Storage::Json,
+            Some(storage) => storage
+                .parse()
+                .map_err(|e| ConfigError::invalid("storage", e))?,
+        };
+
+        let prefix = self.prefix.unwrap_or_else(|| DEFAULT_PREFIX.to_owned());
+        let prefix = prefix.trim_start();
+        if prefix.is_empty() {
+            return Err(ConfigError::invalid("prefix", "must not be empty"));
+        }
+
+        let mut prefixes = BTreeMap::new();
+        for (channel, prefix) in self.prefixes {
+            let channel = channel.trim().trim_start_matches('#').to_lowercase();
+            let prefix = prefix.trim_start();
+            if prefix.is_empty() {
+                return Err(ConfigError::invalid(
+                    &format!("prefixes.{channel}"),
+                    "must not be empty",
+                ));
+            }
+            prefixes.insert(channel, prefix.to_owned());
+        }
+
+        let list_size = self.list_size.unwrap_or(DEFAULT_LIST_SIZE);
+        if list_size == 0 {
+            return Err(ConfigError::invalid("list_size", "must be at least 1"));
+        }
+
+        let mut permissions = Permissions::default();
+        for (name, role) in &self.permissions {
+            permissions
+                .set(name, role)
+                .map_err(|e| ConfigError::invalid(&format!("permissions.{name}"), e))?;
+        }
+
+        let mut cooldowns = Cooldowns::default();
+        for (name, seconds) in &self.global_cooldowns {
+            cooldowns
+                .set_global(name, Duration::from_secs(*seconds))
+                .map_err(|e| ConfigError::invalid(&format!("global_cooldowns.{name}"), e))?;
+        }
+        for (name, seconds) in &self.user_cooldowns {
+            cooldowns
+                .set_user(name, Duration::from_secs(*seconds))
+                .map_err(|e| ConfigError::invalid(&format!("user_cooldowns.{name}"), e))?;
+        }
+
+        Ok(Config {
+            bot_username,
+            oauth_token,
+            refreshing,
+            channels,
+            data_dir,
+            messages_dir,
+            storage,
+            prefix: prefix.to_owned(),
+            prefixes,
+            list_size,
+            autosave_interval: Duration::from_secs(
+                self.autosave_interval.unwrap_or(DEFAULT_AUTOSAVE_INTERVAL),
+            ),
+            backup_count: self.backup_count.unwrap_or(DEFAULT_BACKUP_COUNT),
+            announce_reconnect: self.announce_reconnect.unwrap_or(false),
+            verified_bot: self.verified_bot.unwrap_or(false),
+            whisper_channel,
+            permissions,
+            cooldowns,
+            path: None,
+        })
+    }
+}
+
+pub fn usage() -> String {
+    let flags: Vec<_> = KEYS
+        .iter()
+        .map(|key| format!("[--{} <value>]", key.replace('_', "-")))
+        .collect();
+    format!(
+        "Usage: twitch-queue-bot [--config <file>] {}",
+        flags.join(" ")
+    )
+}
+
+/// Command line flags
+#[derive(Default)]
+struct Args {
+    /// Config file to read instead of the default one
+    path: Option<PathBuf>,
+    /// Keys and the values they are set to
+    overrides: Vec<(String, String)>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, ConfigError> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::Usage(usage()));
+        };
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag.to_owned(), value.to_owned()),
+            None if flag == "help" => return Err(ConfigError::Usage(usage())),
+            None => (
+                flag.to_owned(),
+                args.next().ok_or_else(|| ConfigError::Usage(usage()))?,
+            ),
+        };
+        match flag.as_str() {
+            "config" => parsed.path = Some(value.into()),
+            flag => parsed.overrides.push((flag.replace('-', "_"), value)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Reads the config file and applies the environment and `args`, which
+/// don't include the program name
+pub fn load(args: impl IntoIterator<Item = String>) -> Result<Config, ConfigError> {
+    load_from(args, |name| std::env::var(name).ok())
+}
+
+fn load_from(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Config, ConfigError> {
+    let Args { path, overrides } = parse_args(args)?;
+    let path = path.or_else(|| env(&format!("{ENV_PREFIX}CONFIG")).map(PathBuf::from));
+    let (mut raw, path) = match path {
+        Some(path) => (RawConfig::read(&path)?, Some(path)),
+        None if Path::new(DEFAULT_PATH).exists() => (
+            RawConfig::read(Path::new(DEFAULT_PATH))?,
+            Some(DEFAULT_PATH.into()),
+        ),
+        None if Path::new(LEGACY_PATH).exists() => {
+            warn!("Reading {LEGACY_PATH}, consider moving the config to {DEFAULT_PATH}");
+            (
+                RawConfig::read_legacy(Path::new(LEGACY_PATH))?,
+                Some(LEGACY_PATH.into()),
+            )
+        }
+        None => (RawConfig::default(), None),
+    };
+    for key in KEYS {
+        if let Some(value) = env(&format!("{ENV_PREFIX}{}", key.to_uppercase())) {
+            raw.set(key, &value)?;
+        }
+    }
+    for (key, value) in overrides {
+        raw.set(&key, &value)?;
+    }
+    let mut config = raw.validate()?;
+    config.path = path;
+    Ok(config)
+}
+
+/// Notifies when the config should be reloaded: on SIGHUP, and when the
+/// file at `path` is modified
+pub fn watch(path: Option<PathBuf>) -> mpsc::Receiver<()> {
+    // Reloads requested while one is pending are merged into it
+    let (tx, rx) = mpsc::channel(1);
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    warn!("Couldn't listen for SIGHUP: {e}");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                if let Err(TrySendError::Closed(())) = tx.try_send(()) {
+                    break;
+                }
+            }
+        });
+    }
+    if let Some(path) = path {
+        tokio::spawn(async move {
+            let modified = || fs::metadata(&path).and_then(|m| m.modified()).ok();
+            let mut last = modified();
+            let mut ticker = interval(WATCH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let current = modified();
+                if current == last {
+                    continue;
+                }
+                last = current;
+                info!("{} changed, reloading config", path.display());
+                if let Err(TrySendError::Closed(())) = tx.try_send(()) {
+                    break;
+                }
+            }
+        });
+    }
+    rx
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::{load_from, ConfigError, Storage};
+use crate::bot::{Login, Role};
+use crate::command::lookup;
+use crate::testing::temp_path;
+
+/// Writes `contents` to a temporary config file and returns the flag selecting it
+fn config_file(name: &str, contents: &str) -> (PathBuf, Vec<String>) {
+    let path = temp_path(&format!("config-{name}"));
+    std::fs::write(&path, contents).unwrap();
+    let args = vec!["--config".to_owned(), path.display().to_string()];
+    (path, args)
+}
+
+fn load(
+    name: &str,
+    contents: &str,
+    env: &[(&str, &str)],
+    flags: &[&str],
+) -> Result<super::Config, ConfigError> {
+    let (path, mut args) = config_file(name, contents);
+    args.extend(flags.iter().map(|flag| flag.to_string()));
+    let env: HashMap<_, _> = env
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let config = load_from(args, |name| env.get(name).cloned());
+    std::fs::remove_file(path).unwrap();
+    config
+}
+
+const MINIMAL: &str = r#"
+bot_username = "QueueBot"
+oauth_token = "secret"
+"#;
+
+#[test]
+fn defaults() {
+    let config = load("defaults", MINIMAL, &[], &[]).unwrap();
+    assert_eq!(config.bot_username, "queuebot");
+    assert!(config.channels.is_empty());
+    assert_eq!(config.data_dir, PathBuf::from("data/"));
+    assert_eq!(config.messages_dir, PathBuf::from("data/"));
+    assert_eq!(config.storage, Storage::Json);
+    assert_eq!(config.prefix, "!");
+    assert_eq!(config.list_size, 5);
+    assert_eq!(config.autosave_interval, Duration::from_secs(30));
+    assert_eq!(config.backup_count, 5);
+    assert!(!config.announce_reconnect);
+}
+
+#[test]
+fn toml_values() {
+    let toml = r#"
+bot_username = "queuebot"
+oauth_token = "secret"
+channels = ["Alice", "bob"]
+data_dir = "/var/lib/queues"
+messages_dir = "/etc/queuebot/messages"
+storage = "memory"
+prefix = "?q "
+list_size = 10
+autosave_interval = 0
+announce_reconnect = true
+verified_bot = true
+
+[prefixes]
+Bob = "%"
+
+[permissions]
+list = "vip"
+
+[user_cooldowns]
+pos = 30
+"#;
+    let config = load("toml", toml, &[], &[]).unwrap();
+    assert_eq!(config.channels, ["alice", "bob"]);
+    assert_eq!(config.data_dir, PathBuf::from("/var/lib/queues"));
+    assert_eq!(config.messages_dir, PathBuf::from("/etc/queuebot/messages"));
+    assert_eq!(config.storage, Storage::Memory);
+    assert_eq!(config.prefix, "?q ");
+    assert_eq!(config.settings().prefix("alice"), "?q ");
+    assert_eq!(config.settings().prefix("bob"), "%");
+    assert_eq!(config.list_size, 10);
+    assert!(config.autosave_interval.is_zero());
+    assert!(config.announce_reconnect);
+    assert!(config.verified_bot);
+    assert_eq!(
+        config.permissions.required(lookup("list").unwrap()),
+        Role::Vip
+    );
+    let position = lookup("position").unwrap();
+    assert_eq!(config.cooldowns.user(position), Duration::from_secs(30));
+    assert!(config.cooldowns.global(position).is_zero());
+}
+
+#[test]
+fn flags_override_environment_override_file() {
+    let env = [
+        ("QUEUE_BOT_LIST_SIZE", "7"),
+        ("QUEUE_BOT_PREFIX", "?q "),
+        ("QUEUE_BOT_CHANNELS", "a,b"),
+    ];
+    let config = load("overrides", MINIMAL, &env, &["--list-size", "8"]).unwrap();
+    assert_eq!(config.list_size, 8);
+    assert_eq!(config.prefix, "?q ");
+    assert_eq!(config.channels, ["a", "b"]);
+    assert_eq!(config.whisper_channel.as_deref(), Some("a"));
+
+    let config = load(
+        "equals",
+        MINIMAL,
+        &env,
+        &["--permissions=reset:broadcaster", "--prefixes", "a:?q ,b:%"],
+    )
+    .unwrap();
+    assert_eq!(config.settings().prefix("a"), "?q ");
+    assert_eq!(config.settings().prefix("b"), "%");
+
+    assert_eq!(
+        config.permissions.required(lookup("reset").unwrap()),
+        Role::Broadcaster
+    );
+
+    let config = load(
+        "cooldowns",
+        MINIMAL,
+        &[("QUEUE_BOT_GLOBAL_COOLDOWNS", "length:10")],
+        &[],
+    )
+    .unwrap();
+    assert_eq!(
+        config.cooldowns.global(lookup("length").unwrap()),
+        Duration::from_secs(10)
+    );
+}
+
+#[test]
+fn errors_name_the_key() {
+    let error = |contents: &str, flags: &[&str]| {
+        load("errors", contents, &[], flags)
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("oauth_token = \"secret\"", &[]),
+        "bot_username must be set in the config"
+    );
+    assert!(error("colour = \"red\"", &[]).contains("unknown field `colour`"));
+    assert!(error("list_size = \"many\"", &[]).contains("list_size"));
+    assert_eq!(
+        error(MINIMAL, &["--list-size", "many"]),
+        "Invalid list_size: many is not a number"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--list-size", "0"]),
+        "Invalid list_size: must be at least 1"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--announce-reconnect", "maybe"]),
+        "Invalid announce_reconnect: maybe is not true or false"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--colour", "red"]),
+        "Unknown config key colour"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--permissions", "list:nobody"]),
+        "Invalid permissions.list: Unknown role nobody"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--user-cooldowns", "length:soon"]),
+        "Invalid user_cooldowns: soon is not a number"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--global-cooldowns", "missing:5"]),
+        "Invalid global_cooldowns.missing: Unknown command missing"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--prefixes", "alice: "]),
+        "Invalid prefixes.alice: must not be empty"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--messages-dir", " "]),
+        "Invalid messages_dir: must not be empty"
+    );
+    assert_eq!(
+        error(MINIMAL, &["--channels", "not a channel"]),
+        "Invalid channels: \"not a channel\" is not a channel name"
+    );
+    assert!(matches!(
+        load("usage", MINIMAL, &[], &["--help"]),
+        Err(ConfigError::Usage(_))
+    ));
+}
+
+#[test]
+fn restart_required() {
+    let config = load("restart", MINIMAL, &[], &[]).unwrap();
+    let reloaded = load(
+        "restart",
+        MINIMAL,
+        &[],
+        &["--prefix", "?", "--list-size", "9"],
+    )
+    .unwrap();
+    assert!(config.restart_required(&reloaded).is_empty());
+    let reloaded = load(
+        "restart",
+        MINIMAL,
+        &[],
+        &["--channels", "other", "--oauth-token", "new"],
+    )
+    .unwrap();
+    assert_eq!(
+        config.restart_required(&reloaded),
+        ["oauth_token", "channels"]
+    );
+}
+
+#[test]
+fn login() {
+    let config = load("static", MINIMAL, &[], &[]).unwrap();
+    assert!(matches!(config.login(), Ok(Login::Static { oauth_token }) if oauth_token == "secret"));
+
+    let dir = temp_path("token");
+    let token_file = dir.join("token.json").display().to_string();
+    let refreshing = r#"
+bot_username = "queuebot"
+client_id = "id"
+client_secret = "shh"
+"#;
+    let flags = ["--token-file", token_file.as_str()];
+    let config = load("refreshing", refreshing, &[], &flags).unwrap();
+    assert_eq!(
+        config.login().unwrap_err().to_string(),
+        format!("Invalid token_file: {token_file} doesn't exist, set refresh_token to create it")
+    );
+    // The static token is used until the token file exists
+    let config = load(
+        "fallback",
+        refreshing,
+        &[],
+        &[&flags[..], &["--oauth-token", "old"]].concat(),
+    )
+    .unwrap();
+    assert!(matches!(config.login(), Ok(Login::Static { .. })));
+
+    let config = load(
+        "seed",
+        refreshing,
+        &[],
+        &[&flags[..], &["--refresh-token", "r"]].concat(),
+    )
+    .unwrap();
+    let Ok(Login::Refreshing { storage, .. }) = config.login() else {
+        panic!("Refreshing login expected");
+    };
+    assert_eq!(storage.path(), dir.join("token.json"));
+    assert!(storage.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        load(
+            "secret",
+            "bot_username = \"a\"\nclient_id = \"id\"",
+            &[],
+            &[]
+        )
+        .unwrap_err()
+        .to_string(),
+        "client_secret must be set in the config"
+    );
+}
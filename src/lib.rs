@@ -0,0 +1,5 @@
+pub mod bot;
+pub mod command;
+pub mod config;
+#[cfg(test)]
+mod testing;
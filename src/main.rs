@@ -1,83 +1,92 @@
-mod bot;
-mod config;
-
-use std::sync::atomic::Ordering;
-
-use bot::{Bot, Config, Message, SendResult};
+use std::{path::Path, process::ExitCode, sync::atomic::Ordering};
 
 use tokio::signal;
-use tracing::{debug, info, warn};
+use tracing::{info, warn};
+use twitch_queue_bot::{
+    bot::{
+        chat,
+        store::{self, StoreResult},
+        Aliases, Bot, ChannelList, ChatClient, JsonFileStore, MemoryStore, Message, Priority,
+        QueueStore,
+    },
+    command::{handle_message, handle_whisper, Origin, Settings},
+    config::{self, Config, Storage},
+};
 
-macro_rules! mod_command {
-    ($is_mod:tt,$user:tt,$b:block) => {
-        match $is_mod {
-            true => {
-                debug!("User {} is a moderator", $user);
-                $b
-            }
-            false => {
-                info!("User {} not authorised to perform mod commands", $user);
-                Ok(())
+/// Opens the configured storage backend for `channel`
+fn open_store(
+    storage: Storage,
+    data_dir: &Path,
+    channel: &str,
+) -> StoreResult<Box<dyn QueueStore>> {
+    let dir = store::channel_dir(data_dir, channel);
+    Ok(match storage {
+        Storage::Memory => {
+            warn!("Using in-memory storage, queues will not be persisted");
+            Box::new(MemoryStore::new())
+        }
+        Storage::Json => Box::new(JsonFileStore::new(&dir)?),
+        #[cfg(feature = "sqlite")]
+        Storage::Sqlite => {
+            let json = JsonFileStore::new(&dir)?;
+            let mut store = store::SqliteStore::open(dir.join("queues.db"))?;
+            if store.is_empty()? {
+                info!(
+                    "Database is empty, importing JSON queues from {}",
+                    dir.display()
+                );
+                let imported = store.import(&json)?;
+                info!("Imported {imported} queues");
             }
+            Box::new(store)
         }
-    };
+    })
 }
 
-const CMD_PREFIX: char = '!';
+/// Applies the settings that can change while the bot is running
+fn apply(bot: &mut Bot, config: &Config, previous: Option<&Config>) {
+    if previous.map(|p| p.autosave_interval) != Some(config.autosave_interval) {
+        if config.autosave_interval.is_zero() {
+            bot.disable_autosave();
+        } else {
+            bot.enable_autosave(config.autosave_interval);
+        }
+    }
+    bot.keep_backups(config.backup_count);
+    bot.set_list_size(config.list_size);
+    bot.set_whisper_channel(config.whisper_channel.as_deref());
+}
 
-#[tracing::instrument(skip(bot))]
-async fn handle_message(bot: &mut Bot, is_mod: bool, user: &str, msg: &str) -> SendResult {
-    info!("Handling message");
-    let msg = match msg.strip_prefix(CMD_PREFIX) {
-        None => {
-            debug!("Not a command, returning");
-            return Ok(());
+/// Reads the config again, keeping the current one if the new one is invalid
+fn reload(bot: &mut Bot, config: &mut Config, settings: &mut Settings) {
+    let new = match config::load(std::env::args().skip(1)) {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("Couldn't reload config, keeping the current one: {e}");
+            return;
         }
-        Some(msg) => msg.trim_end(),
     };
-    let (cmd, args) = match msg.split_once(' ') {
-        None => (msg, None),
-        Some((cmd, args)) => (cmd, Some(args)),
-    };
-    match (cmd.to_lowercase().as_str(), args) {
-        ("join", name) => bot.join(user, name).await,
-        ("name", name) => bot.name(user, name).await,
-        ("leave", _) => bot.leave(user).await,
-        ("position", _) => bot.position(user).await,
-        ("length", _) => bot.length().await,
-        // Mod commands
-        ("next", _) => mod_command!(is_mod, user, { bot.next().await }),
-        ("list", _) => mod_command!(is_mod, user, { bot.list().await }),
-        ("clear", _) => mod_command!(is_mod, user, { bot.clear().await }),
-        ("open", _) => mod_command!(is_mod, user, { bot.open().await }),
-        ("close", _) => mod_command!(is_mod, user, { bot.close().await }),
-        ("reset", _) => mod_command!(is_mod, user, { bot.reset().await }),
-        ("save", _) => mod_command!(is_mod, user, { bot.save().await }),
-        ("select", name) => mod_command!(is_mod, user, {
-            match name {
-                Some(name) => bot.select(name).await,
-                None => {
-                    bot.send_msg("You must provide a name for the queue".into())
-                        .await
-                }
-            }
-        }),
-        ("create", name) => mod_command!(is_mod, user, {
-            match name {
-                Some(name) => bot.create(name).await,
-                None => {
-                    bot.send_msg("You must provide a name for the queue".into())
-                        .await
-                }
-            }
-        }),
-        // Not a command
-        _ => Ok(()),
+    for key in config.restart_required(&new) {
+        warn!("{key} changed, restart the bot to apply it");
     }
+    apply(bot, &new, Some(config));
+    *settings = new.settings();
+    // Keep what the running bot actually uses, so the warnings repeat until a restart
+    let restart_only = std::mem::replace(config, new);
+    config.bot_username = restart_only.bot_username;
+    config.oauth_token = restart_only.oauth_token;
+    config.refreshing = restart_only.refreshing;
+    config.channels = restart_only.channels;
+    config.data_dir = restart_only.data_dir;
+    config.storage = restart_only.storage;
+    config.verified_bot = restart_only.verified_bot;
+    bot.set_template_dir(Some(&config.messages_dir));
+    bot.reload_aliases();
+    info!("Config reloaded");
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     tracing_subscriber::fmt()
         .compact()
         .with_target(false)
@@ -87,19 +96,63 @@ async fn main() {
         .init();
 
     info!("Reading config");
-    let config = config::read().unwrap();
-    let oauth_token = config
-        .get("OAUTH_TOKEN")
-        .expect("OAUTH_TOKEN must be present in the config");
-    let bot_username = config
-        .get("BOT_USERNAME")
-        .expect("BOT_USERNAME must be present in the config");
-    let channel_name = config
-        .get("CHANNEL_NAME")
-        .expect("CHANNEL_NAME must be present in the config");
+    let mut config = match config::load(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut settings = config.settings();
+
+    // Queues of single channel versions belong to the first channel
+    if let Some(first) = config.channels.first() {
+        let token_file = config.refreshing.as_ref().map(|r| r.token_file.as_path());
+        store::adopt_legacy_data(&config.data_dir, first, token_file)
+            .expect("Couldn't move existing queues");
+    }
 
     info!("Creating bot");
-    let mut bot = Bot::new(Config::new(oauth_token, bot_username, channel_name));
+    let login = match config.login() {
+        Ok(login) => login,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let chat_config = chat::Config {
+        verified: config.verified_bot,
+        ..chat::Config::new(
+            login,
+            &config.bot_username,
+            config.channels.iter().map(String::as_str),
+        )
+    };
+    let mut bot = Bot::new(ChatClient::new(chat_config));
+
+    apply(&mut bot, &config, None);
+    bot.set_template_dir(Some(&config.messages_dir));
+
+    for channel in &config.channels {
+        let store = open_store(config.storage, &config.data_dir, channel)
+            .unwrap_or_else(|e| panic!("Couldn't open the queues of channel {channel}: {e}"));
+        bot.add_channel(channel, store);
+    }
+    let (added, aliases) = match config.storage {
+        Storage::Memory => (ChannelList::in_memory(), Aliases::in_memory()),
+        _ => (
+            ChannelList::open(&config.data_dir).expect("Couldn't read channel list"),
+            Aliases::open(&config.data_dir).expect("Couldn't read command aliases"),
+        ),
+    };
+    bot.set_aliases(aliases);
+    let (storage, data_dir) = (config.storage, config.data_dir.clone());
+    bot.enable_channel_admin(
+        &config.bot_username,
+        added,
+        Box::new(move |channel| open_store(storage, &data_dir, channel)),
+    )
+    .expect("Couldn't join added channels");
 
     let closed = bot.chat.closed.clone();
     tokio::spawn(async move {
@@ -115,25 +168,73 @@ async fn main() {
         }
     });
 
-    bot.send_msg(format!(
-        "Hello there gamers! {bot_username} is now in chat.",
-    ))
-    .await
-    .expect("Unable to send greeting");
+    let channels: Vec<String> = bot.channels().map(str::to_owned).collect();
+    for channel in &channels {
+        let greeting = bot
+            .templates(channel)
+            .render("greeting", &[("user", &config.bot_username)]);
+        bot.send_msg(channel, greeting, Priority::Low)
+            .await
+            .expect("Unable to send greeting");
+    }
 
+    let mut reloads = config::watch(config.path.clone());
     loop {
-        match bot.recv_msg().await {
+        let msg = tokio::select! {
+            msg = bot.recv_msg() => msg,
+            Some(()) = reloads.recv() => {
+                reload(&mut bot, &mut config, &mut settings);
+                continue;
+            }
+        };
+        match msg {
             None => {
                 break;
             }
             Some(msg) => match msg {
-                Message::UserText(is_mod, user, text) => {
-                    if let Err(e) = handle_message(&mut bot, is_mod, &user, &text).await {
+                Message::UserText {
+                    channel,
+                    role,
+                    user,
+                    text,
+                    id,
+                } => {
+                    if let Err(e) = handle_message(
+                        &mut bot,
+                        &settings,
+                        &channel,
+                        role,
+                        &user,
+                        Origin::Chat(id.as_deref()),
+                        &text,
+                    )
+                    .await
+                    {
                         warn!("Couldn't send message: {e}");
                     };
+                    bot.flush_journal();
+                }
+                Message::Whisper { user, text } => {
+                    if let Err(e) = handle_whisper(&mut bot, &settings, &user, &text).await {
+                        warn!("Couldn't send message: {e}");
+                    };
+                    bot.flush_journal();
+                }
+                Message::Reconnected => {
+                    if !config.announce_reconnect {
+                        continue;
+                    }
+                    let channels: Vec<String> = bot.channels().map(str::to_owned).collect();
+                    for channel in &channels {
+                        let msg = bot.templates(channel).text("reconnected");
+                        if let Err(e) = bot.send_msg(channel, msg, Priority::High).await {
+                            warn!("Couldn't send message: {e}");
+                        }
+                    }
                 }
             },
         }
     }
     info!("Bot exited");
+    ExitCode::SUCCESS
 }